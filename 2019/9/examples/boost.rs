@@ -0,0 +1,66 @@
+// Run a Day 9 BOOST program through the library API.
+//
+//     cargo run --example boost [path] [input]
+//
+// With no path, runs the sample programs from the puzzle instead, so the
+// example works without an input.txt.
+use aoc9::{parse_program, process_input, CpuBuilder, RunState, Word};
+use std::path::Path;
+use std::process::exit;
+
+// Programs from the day 9 puzzle text: a quine and two that output a
+// large number.
+const SAMPLES: &[&str] = &[
+    "109,1,204,-1,1001,100,1,100,1008,100,16,101,1006,101,0,99",
+    "1102,34915192,34915192,7,4,7,99,0",
+    "104,1125899906842624,99",
+];
+
+// Run |program| with |input| queued, returning its outputs.
+fn boost(program: Vec<Word>, input: Word) -> Result<Vec<Word>, String> {
+    let mut cpu = CpuBuilder::new()
+        .program(program)
+        .input(input)
+        .quiet()
+        .build();
+    match cpu.resume() {
+        Ok(RunState::Halted) => Ok(cpu.outputs().to_vec()),
+        Ok(state) => Err(format!("stopped early: {:?}", state)),
+        Err(e) => Err(format!("cpu fault: {}", cpu.trap(&e))),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let programs = match args.first() {
+        Some(path) => match process_input(Path::new(path)) {
+            Ok(program) => vec![program],
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                exit(1);
+            }
+        },
+        None => SAMPLES
+            .iter()
+            .filter_map(|src| parse_program(src).ok())
+            .collect(),
+    };
+    let input = match args.get(1).map(|s| s.parse()) {
+        None => 1,
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            eprintln!("bad input: {}", e);
+            exit(1);
+        }
+    };
+
+    for program in programs {
+        match boost(program, input) {
+            Ok(outputs) => println!("{:?}", outputs),
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(1);
+            }
+        }
+    }
+}
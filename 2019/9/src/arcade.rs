@@ -0,0 +1,100 @@
+use crate::{Cpu, Word};
+use std::collections::HashMap;
+
+pub const EMPTY: Word = 0;
+pub const WALL: Word = 1;
+pub const BLOCK: Word = 2;
+pub const PADDLE: Word = 3;
+pub const BALL: Word = 4;
+
+// Screen for the day 13 arcade cabinet. The program draws with output
+// triples (x, y, tile), except that x = -1, y = 0 sets the score.
+#[derive(Default)]
+pub struct ArcadeScreen {
+    tiles: HashMap<(Word, Word), Word>,
+    score: Word,
+    // Cpu outputs already drawn.
+    consumed: usize,
+}
+
+impl ArcadeScreen {
+    pub fn new() -> ArcadeScreen {
+        ArcadeScreen::default()
+    }
+
+    // Draw the complete triples |cpu| has output since the last update.
+    pub fn update(&mut self, cpu: &Cpu) {
+        let pending = &cpu.outputs()[self.consumed..];
+        for triple in pending.chunks_exact(3) {
+            match *triple {
+                [-1, 0, score] => self.score = score,
+                [x, y, tile] => {
+                    self.tiles.insert((x, y), tile);
+                }
+                _ => unreachable!(),
+            }
+        }
+        self.consumed += pending.len() - pending.len() % 3;
+    }
+
+    pub fn score(&self) -> Word {
+        self.score
+    }
+
+    pub fn count_tiles(&self, tile: Word) -> usize {
+        self.tiles.values().filter(|&&t| t == tile).count()
+    }
+
+    // Draw the screen from (0, 0), one line per row.
+    pub fn render(&self) -> String {
+        let width = self.tiles.keys().map(|p| p.0 + 1).max().unwrap_or(0);
+        let height = self.tiles.keys().map(|p| p.1 + 1).max().unwrap_or(0);
+        let mut out = String::new();
+        for y in 0..height {
+            for x in 0..width {
+                out.push(match self.tiles.get(&(x, y)).copied().unwrap_or(EMPTY) {
+                    WALL => '#',
+                    BLOCK => '=',
+                    PADDLE => '-',
+                    BALL => 'o',
+                    _ => ' ',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draw_tiles() {
+        let triples = [
+            [0, 0, WALL],
+            [1, 0, BLOCK],
+            [2, 0, BLOCK],
+            [1, 1, BALL],
+            [-1, 0, 12345],
+            [2, 2, PADDLE],
+            [2, 0, EMPTY],
+        ];
+        let mut prog = Vec::new();
+        for &v in triples.iter().flatten() {
+            prog.extend_from_slice(&[104, v]);
+        }
+        prog.extend_from_slice(&[104, 5, 104, 5, 99]);
+        let mut cpu = Cpu::new(Some(prog));
+        cpu.verbose = false;
+        cpu.resume().unwrap();
+
+        let mut screen = ArcadeScreen::new();
+        screen.update(&cpu);
+        assert_eq!(screen.score(), 12345);
+        assert_eq!(screen.count_tiles(BLOCK), 1);
+        assert_eq!(screen.render(), "#= \n o \n  -\n");
+        assert_eq!(screen.consumed, 21);
+    }
+}
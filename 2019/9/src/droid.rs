@@ -0,0 +1,70 @@
+use crate::{Cpu, Word};
+
+pub const NORTH: u8 = 1;
+pub const SOUTH: u8 = 2;
+pub const WEST: u8 = 3;
+pub const EAST: u8 = 4;
+
+pub const WALL: u8 = 0;
+pub const MOVED: u8 = 1;
+pub const OXYGEN: u8 = 2;
+
+// Repair droid for day 15. Only knows how to make a single move and where
+// it is; exploring the maze is left to the caller.
+pub struct Droid {
+    cpu: Cpu,
+    // Position with y increasing southward.
+    pos: (i32, i32),
+}
+
+impl Droid {
+    pub fn new(mut cpu: Cpu) -> Droid {
+        cpu.verbose = false;
+        cpu.interactive = false;
+        Droid { cpu, pos: (0, 0) }
+    }
+
+    pub fn pos(&self) -> (i32, i32) {
+        self.pos
+    }
+
+    // Ask the droid to move one step in |dir| and return its status. The
+    // position only changes if it didn't hit a wall. Panics if the program
+    // faults or stops answering.
+    pub fn try_move(&mut self, dir: u8) -> u8 {
+        self.cpu.push_input(Word::from(dir));
+        let status = match self.cpu.run_until_output() {
+            Ok(Some(status)) => status as u8,
+            Ok(None) => panic!("droid stopped before answering"),
+            Err(e) => panic!("cpu fault: {}", self.cpu.trap(&e)),
+        };
+        if status != WALL {
+            let (x, y) = self.pos;
+            self.pos = match dir {
+                NORTH => (x, y - 1),
+                SOUTH => (x, y + 1),
+                WEST => (x - 1, y),
+                _ => (x + 1, y),
+            };
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_moves() {
+        // Answer each command with its value minus one.
+        let prog = vec![3, 100, 1001, 100, -1, 100, 4, 100, 1105, 1, 0];
+        let mut droid = Droid::new(Cpu::new(Some(prog)));
+        assert_eq!(droid.try_move(NORTH), WALL);
+        assert_eq!(droid.pos(), (0, 0));
+        assert_eq!(droid.try_move(SOUTH), MOVED);
+        assert_eq!(droid.pos(), (0, 1));
+        assert_eq!(droid.try_move(WEST), OXYGEN);
+        assert_eq!(droid.pos(), (-1, 1));
+    }
+}
@@ -0,0 +1,3828 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{stdin, stdout};
+use std::io::{Read, Write};
+use std::num::ParseIntError;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+pub mod arcade;
+pub mod droid;
+pub mod network;
+pub mod robot;
+pub use arcade::ArcadeScreen;
+pub use droid::Droid;
+pub use network::Packet;
+pub use robot::Robot;
+
+pub const INPUT_FILE: &str = "input.txt";
+
+// Memory cell width. 64 bits is what the puzzles need; build with the
+// `wide` feature for 128-bit cells, so that multiplying two large 64-bit
+// values doesn't overflow.
+#[cfg(not(feature = "wide"))]
+pub type Word = i64;
+#[cfg(feature = "wide")]
+pub type Word = i128;
+
+// Default cap on dense memory, in cells.
+pub const MAX_MEMORY: usize = 1 << 26;
+
+// Handler for an opcode added with Cpu::register_op.
+pub type OpHandler = Box<dyn FnMut(&mut Cpu, &[Parameter])>;
+
+// Opcode names indexed by opcode, with HALT (99) in slot 0.
+const OP_NAMES: [&str; 10] = [
+    "HALT", "ADD", "MUL", "INPUT", "OUTPUT", "JNZ", "JZ", "LESSTHAN", "EQUALS", "RELBASE",
+];
+
+// Backing store for cpu memory. Cells that were never written read as 0.
+pub trait Memory {
+    fn read(&self, addr: usize) -> Word;
+    fn write(&mut self, addr: usize, val: Word);
+}
+
+// Dense memory, grown with zeroes on write to fit the address.
+impl Memory for Vec<Word> {
+    fn read(&self, addr: usize) -> Word {
+        self.get(addr).copied().unwrap_or(0)
+    }
+
+    fn write(&mut self, addr: usize, val: Word) {
+        if addr >= self.len() {
+            self.resize(addr + 1, 0);
+        }
+        self[addr] = val;
+    }
+}
+
+// Memory holding only the cells that have been written, for programs that
+// address far beyond their own length.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SparseMemory(pub HashMap<usize, Word>);
+
+impl Memory for SparseMemory {
+    fn read(&self, addr: usize) -> Word {
+        self.0.get(&addr).copied().unwrap_or(0)
+    }
+
+    fn write(&mut self, addr: usize, val: Word) {
+        self.0.insert(addr, val);
+    }
+}
+
+// Number of parameters taken by |opcode|, or 0 for HALT and anything that
+// isn't an opcode.
+pub fn arity(opcode: Word) -> usize {
+    match opcode % 100 {
+        1 | 2 | 7 | 8 => 3,
+        5 | 6 => 2,
+        3 | 4 | 9 => 1,
+        _ => 0,
+    }
+}
+
+// The mode digits for the first |count| parameters of the instruction
+// |instr|, lowest parameter first. Digits are returned as they are, even if
+// they aren't a valid mode.
+pub fn decode_modes(instr: Word, count: usize) -> Vec<u8> {
+    let mut flags = instr.abs() / 100;
+    let mut modes = Vec::with_capacity(count);
+    for _ in 0..count {
+        modes.push((flags % 10) as u8);
+        flags /= 10;
+    }
+    modes
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Parameter {
+    Position(Word),
+    Immediate(Word),
+    Relative(Word),
+}
+
+impl fmt::Debug for Parameter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Parameter::Position(v) => write!(f, "P({})", v),
+            Parameter::Immediate(v) => write!(f, "I({})", v),
+            Parameter::Relative(v) => write!(f, "R({})", v),
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    ADD([Parameter; 3]),
+    MUL([Parameter; 3]),
+    INPUT([Parameter; 1]),
+    OUTPUT([Parameter; 1]),
+    JUMP(bool, [Parameter; 2]),
+    LESSTHAN([Parameter; 3]),
+    EQUALS([Parameter; 3]),
+    RELBASE([Parameter; 1]),
+    HALT,
+    // An opcode added with Cpu::register_op, with its arity and the
+    // parameters it takes.
+    CUSTOM(Word, usize, [Parameter; 3]),
+}
+
+impl Instruction {
+    pub fn params(&self) -> &[Parameter] {
+        match self {
+            Instruction::ADD(args)
+            | Instruction::MUL(args)
+            | Instruction::LESSTHAN(args)
+            | Instruction::EQUALS(args) => args,
+            Instruction::INPUT(args) | Instruction::OUTPUT(args) | Instruction::RELBASE(args) => {
+                args
+            }
+            Instruction::JUMP(_, args) => args,
+            Instruction::HALT => &[],
+            Instruction::CUSTOM(_, n, args) => &args[..*n],
+        }
+    }
+}
+
+// One executed instruction. Relative parameters are resolved against the
+// relative base at the time, so they appear as positions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub cycle: u64,
+    pub ip: usize,
+    pub op: &'static str,
+    pub params: Vec<Parameter>,
+    pub rbase: Word,
+}
+
+// What one executed instruction changed, so Cpu::undo can put it back.
+#[derive(Debug, Clone, Default)]
+struct UndoStep {
+    ip: usize,
+    rbase: Word,
+    // Cells written as (addr, old value), in the order written.
+    writes: Vec<(usize, Word)>,
+    // Value taken from the input queue, if any.
+    input: Option<Word>,
+    outputs: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CpuError {
+    // Opcode and the address it was fetched from.
+    InvalidOpcode(Word, usize),
+    InvalidMode(Word),
+    OutOfBounds(usize),
+    // Execution reached this address, past the end of memory, without
+    // ever executing HALT.
+    RanOffEnd(usize),
+    // A parameter or jump target resolved to a negative address.
+    NegativeAddress(Word),
+    // A write would grow memory past max_memory.
+    MemoryLimitExceeded(usize),
+    // Text queued as ASCII input contained this character.
+    NonAscii(char),
+    // INPUT ran out of input with EofAction::Error set, or stdin ended
+    // with no on_eof set.
+    UnexpectedEof,
+    // Adjusting the relative base, or offsetting from it, overflowed.
+    RelBaseOverflow,
+    // The program file couldn't be read or parsed, see run_file.
+    Load(String),
+    // ADD or MUL overflowed with Overflow::Error set.
+    Overflow,
+    // A line read from stdin wasn't a number, or the terminal couldn't be
+    // used for the prompt.
+    BadInput(String),
+}
+
+// Convert a value used as an address, rejecting negative ones rather than
+// letting them wrap to a huge index.
+fn addr(v: Word) -> Result<usize, CpuError> {
+    if v < 0 {
+        return Err(CpuError::NegativeAddress(v));
+    }
+    usize::try_from(v).map_err(|_| CpuError::OutOfBounds(usize::MAX))
+}
+
+// A command typed at the monitor prompt, see Cpu::monitor.
+#[derive(Debug, PartialEq)]
+pub enum MonitorCmd {
+    Step,
+    Continue,
+    Memory(usize),
+    Registers,
+    Break(usize),
+    Quit,
+}
+
+// Parse a monitor command line, returning None for anything unrecognised.
+pub fn parse_command(line: &str) -> Option<MonitorCmd> {
+    let mut words = line.split_whitespace();
+    let cmd = match (words.next()?, words.next()) {
+        ("s", None) => MonitorCmd::Step,
+        ("c", None) => MonitorCmd::Continue,
+        ("r", None) => MonitorCmd::Registers,
+        ("q", None) => MonitorCmd::Quit,
+        ("m", Some(addr)) => MonitorCmd::Memory(addr.parse().ok()?),
+        ("b", Some(addr)) => MonitorCmd::Break(addr.parse().ok()?),
+        _ => return None,
+    };
+    match words.next() {
+        Some(_) => None,
+        None => Some(cmd),
+    }
+}
+
+// How INPUT behaves once the input queue is empty.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum InputMode {
+    // Read from stdin, or pause with RunState::NeedInput when not
+    // interactive.
+    Blocking,
+    // Fall back on on_eof straight away, reading -1 unless it's set, and
+    // mark the cpu idle, as day 23 expects.
+    NonBlocking,
+}
+
+// What INPUT does when there is nothing left to read: stdin is at EOF, or
+// the queue and any input channel are empty in non-blocking mode.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum EofAction {
+    // Stop as if halted, leaving ip on the INPUT so it can be retried.
+    Halt,
+    // Read this value instead.
+    Value(Word),
+    // Fail with CpuError::UnexpectedEof.
+    Error,
+}
+
+// What ADD and MUL do when the result doesn't fit in a Word. Either way
+// the outcome is the same in debug and release builds.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Overflow {
+    // Wrap around in two's complement.
+    Wrap,
+    // Fail with CpuError::Overflow.
+    Error,
+}
+
+// Outcome of executing a single instruction, or why a run paused.
+#[derive(Debug, PartialEq)]
+pub enum RunState {
+    Continue,
+    Halted,
+    NeedInput,
+    // Paused before executing the instruction at this address.
+    Breakpoint(usize),
+    // Paused after an instruction wrote to a watched address.
+    Watchpoint { addr: usize, old: Word, new: Word },
+    // Paused at this address having used up max_cycles.
+    CycleLimitExceeded(usize),
+    // run_timeout ran out of time before the program stopped.
+    Timeout,
+    // Paused after outputting this value with halt_on_output set.
+    OutputReady(Word),
+}
+
+// Snapshots cover the machine state and counters. Debugger state and
+// channel links are left out and come back empty on restore.
+#[derive(Serialize, Deserialize)]
+pub struct Cpu {
+    ip: usize,
+    rbase: Word,
+    pub memory: Vec<Word>,
+    // When set, memory stays the size of the program and cells past its
+    // end live here instead.
+    sparse: Option<SparseMemory>,
+    // Program as loaded, for reset.
+    initial: Vec<Word>,
+    inputs: VecDeque<Word>,
+    outputs: Vec<Word>,
+    // Echo OUTPUT values to stdout as they are produced. The instruction
+    // trace is logged separately at debug level.
+    pub verbose: bool,
+    // Echo ASCII range outputs as the characters they encode, e.g. to play
+    // the day 25 adventure. Larger values are still echoed as numbers.
+    pub ascii_output: bool,
+    // Reject instructions with nonzero mode digits beyond their parameters,
+    // e.g. 11104, with CpuError::InvalidMode.
+    pub strict: bool,
+    // Read from stdin when the input queue runs dry rather than pausing
+    // with RunState::NeedInput.
+    pub interactive: bool,
+    pub input_mode: InputMode,
+    // What INPUT does when there's nothing to read. Unset, an empty queue
+    // or channel reads -1 as day 23 expects, and the end of stdin fails
+    // with CpuError::UnexpectedEof.
+    pub on_eof: Option<EofAction>,
+    pub on_overflow: Overflow,
+    // Read this whenever the input queue is empty instead of touching
+    // stdin, e.g. for puzzles that take the same input throughout.
+    pub default_input: Option<Word>,
+    // Pause with RunState::OutputReady after every OUTPUT, e.g. to probe
+    // what a program prints first.
+    pub halt_on_output: bool,
+    // Log a warning when a jump lands in the middle of an instruction, as
+    // far as is known from the code executed so far and a walk of the
+    // loaded program up to its first HALT.
+    pub align_check: bool,
+    // Record writes to opcodes, those executed so far or found walking the
+    // loaded program, see self_modifications.
+    pub detect_self_mod: bool,
+    #[serde(skip)]
+    self_mods: Vec<(usize, Word, Word)>,
+    // Which addresses of the loaded program start an instruction, built
+    // when first needed by align_check or detect_self_mod.
+    #[serde(skip)]
+    code_starts: Option<Vec<bool>>,
+    // What each address has been decoded as so far: true for an opcode,
+    // false for a parameter only, None if never reached.
+    #[serde(skip)]
+    decoded: Vec<Option<bool>>,
+    // Last INPUT found nothing to read and got -1 instead.
+    idle: bool,
+    // Stop with RunState::CycleLimitExceeded once this many instructions
+    // have executed, to escape programs that never halt.
+    pub max_cycles: Option<u64>,
+    // Writes that would grow dense memory to this many cells or more fail
+    // with CpuError::MemoryLimitExceeded instead of allocating.
+    pub max_memory: usize,
+    // When set, every executed instruction is recorded here.
+    #[serde(skip)]
+    pub trace_sink: Option<Vec<Trace>>,
+    // When non-empty, only these opcodes are traced.
+    #[serde(skip)]
+    pub trace_filter: HashSet<Word>,
+    #[serde(skip)]
+    breakpoints: HashSet<usize>,
+    // Breakpoint last reported, so resuming steps past it.
+    #[serde(skip)]
+    break_hit: Option<usize>,
+    #[serde(skip)]
+    watchpoints: HashSet<usize>,
+    // Watched write made by the current instruction as (addr, old, new).
+    #[serde(skip)]
+    watch_hit: Option<(usize, Word, Word)>,
+    // Network links to other cpus, see connect_output.
+    #[serde(skip)]
+    output_tx: Vec<Sender<Word>>,
+    #[serde(skip)]
+    input_rx: Option<Receiver<Word>>,
+    // Called with each OUTPUT value as it is produced, see on_output.
+    #[serde(skip)]
+    output_hooks: Vec<Box<dyn FnMut(Word)>>,
+    // Address ranges handled by a device rather than memory, see map.
+    #[serde(skip)]
+    devices: Vec<(Range<usize>, Box<dyn Memory>)>,
+    // Arity and handler of each opcode added with register_op. The
+    // handler is taken out while it runs.
+    #[serde(skip)]
+    custom_ops: HashMap<Word, (usize, Option<OpHandler>)>,
+    // Executions per opcode, indexed as OP_NAMES.
+    op_counts: [u64; 10],
+    // Time each opcode's instructions and count memory accesses as they
+    // execute, see timing and access_stats.
+    pub profile: bool,
+    // How many executed instructions undo can step back over. 0 records
+    // nothing.
+    pub undo_depth: usize,
+    #[serde(skip)]
+    undo_log: VecDeque<UndoStep>,
+    // Time spent per opcode while profiling, indexed as OP_NAMES.
+    #[serde(skip)]
+    op_times: [Duration; 10],
+    // Parameter reads and writes per address while profiling.
+    #[serde(skip)]
+    access: HashMap<usize, (u64, u64)>,
+    // Instructions executed over the cpu's lifetime.
+    cycles: u64,
+    // Address of the HALT the program stopped on.
+    halt_ip: Option<usize>,
+}
+
+impl Cpu {
+    pub fn new(mem: Option<Vec<Word>>) -> Cpu {
+        let memory = match mem {
+            Some(m) => m,
+            None => process_input(Path::new(INPUT_FILE)).unwrap(),
+        };
+        Cpu {
+            ip: 0,
+            rbase: 0,
+            initial: memory.clone(),
+            memory,
+            sparse: None,
+            inputs: VecDeque::new(),
+            outputs: Vec::new(),
+            verbose: true,
+            ascii_output: false,
+            strict: false,
+            interactive: true,
+            input_mode: InputMode::Blocking,
+            on_eof: None,
+            on_overflow: Overflow::Wrap,
+            default_input: None,
+            halt_on_output: false,
+            align_check: false,
+            detect_self_mod: false,
+            self_mods: Vec::new(),
+            code_starts: None,
+            decoded: Vec::new(),
+            idle: false,
+            max_cycles: None,
+            max_memory: MAX_MEMORY,
+            trace_sink: None,
+            trace_filter: HashSet::new(),
+            breakpoints: HashSet::new(),
+            break_hit: None,
+            watchpoints: HashSet::new(),
+            watch_hit: None,
+            output_tx: Vec::new(),
+            input_rx: None,
+            output_hooks: Vec::new(),
+            devices: Vec::new(),
+            custom_ops: HashMap::new(),
+            op_counts: [0; 10],
+            profile: false,
+            undo_depth: 0,
+            undo_log: VecDeque::new(),
+            op_times: [Duration::ZERO; 10],
+            access: HashMap::new(),
+            cycles: 0,
+            halt_ip: None,
+        }
+    }
+
+    // Put the cpu back to how it was loaded so the program can be rerun,
+    // e.g. with different phase settings. Debugger state, links and the
+    // counters are kept.
+    pub fn reset(&mut self) {
+        self.memory = self.initial.clone();
+        if self.sparse.is_some() {
+            self.sparse = Some(SparseMemory::default());
+        }
+        self.ip = 0;
+        self.rbase = 0;
+        self.inputs.clear();
+        self.outputs.clear();
+        self.idle = false;
+        self.halt_ip = None;
+        self.break_hit = None;
+        self.watch_hit = None;
+        self.undo_log.clear();
+        self.self_mods.clear();
+        self.decoded.clear();
+    }
+
+    // Step back over the last executed instruction, restoring ip, rbase,
+    // the memory it wrote, any input it took from the queue and any output
+    // it produced. Input read from stdin or a channel can't be given back.
+    // Returns false once there is nothing left to undo.
+    pub fn undo(&mut self) -> bool {
+        let step = match self.undo_log.pop_back() {
+            Some(step) => step,
+            None => return false,
+        };
+        for &(addr, old) in step.writes.iter().rev() {
+            self.backing_mut(addr).write(addr, old);
+        }
+        if let Some(v) = step.input {
+            self.inputs.push_front(v);
+        }
+        self.outputs.truncate(step.outputs);
+        self.ip = step.ip;
+        self.rbase = step.rbase;
+        self.halt_ip = None;
+        true
+    }
+
+    // Read or patch memory directly, e.g. to set the noun and verb in day 2
+    // before running. Unwritten cells read as 0 and poking past the end
+    // grows memory to fit.
+    pub fn peek(&self, addr: usize) -> Word {
+        self.backing(addr).read(addr)
+    }
+
+    pub fn poke(&mut self, addr: usize, val: Word) {
+        self.backing_mut(addr).write(addr, val);
+    }
+
+    // Overlay |data| on memory from |offset|, growing it to fit.
+    pub fn load_at(&mut self, offset: usize, data: &[Word]) {
+        for (i, &v) in data.iter().enumerate() {
+            self.poke(offset + i, v);
+        }
+    }
+
+    // Format |len| cells from |start|, |cols| to a line, each line
+    // prefixed with the address of its first cell.
+    pub fn dump(&self, start: usize, len: usize, cols: usize) -> String {
+        let mut out = String::new();
+        for row in (start..start + len).step_by(cols.max(1)) {
+            let end = (row + cols.max(1)).min(start + len);
+            let cells: Vec<String> = (row..end).map(|a| self.peek(a).to_string()).collect();
+            out.push_str(&format!("{:04}: {}\n", row, cells.join(" ")));
+        }
+        out
+    }
+
+    // Write memory back out in the comma-separated form parse_program reads.
+    // Zeros the program grew into past its original length are trimmed.
+    pub fn to_program_string(&self) -> String {
+        let keep = self.initial.len().min(self.memory.len());
+        let end = self.memory[keep..]
+            .iter()
+            .rposition(|&v| v != 0)
+            .map_or(keep, |i| keep + i + 1);
+        let cells: Vec<String> = self.memory[..end].iter().map(Word::to_string).collect();
+        cells.join(",")
+    }
+
+    // Cells that differ from the program as loaded, as (addr, old, new).
+    // Sparse cells are compared against 0.
+    pub fn diff(&self) -> Vec<(usize, Word, Word)> {
+        let mut changes = memory_diff(&self.initial, &self.memory);
+        if let Some(sparse) = &self.sparse {
+            let mut cells: Vec<_> = sparse.0.iter().filter(|&(_, &v)| v != 0).collect();
+            cells.sort();
+            changes.extend(cells.into_iter().map(|(&addr, &v)| (addr, 0, v)));
+        }
+        changes
+    }
+
+    // Queue a value to be consumed by the next INPUT instruction ahead of
+    // falling back to stdin.
+    pub fn push_input(&mut self, v: Word) {
+        self.inputs.push_back(v);
+    }
+
+    // All values produced by OUTPUT instructions so far, oldest first.
+    pub fn outputs(&self) -> &[Word] {
+        &self.outputs
+    }
+
+    // Queued inputs not yet consumed, not counting any input channel.
+    pub fn pending_input(&self) -> usize {
+        self.inputs.len()
+    }
+
+    // Outputs buffered since the last drain, e.g. to tell when a whole
+    // day 23 packet is ready.
+    pub fn pending_output(&self) -> usize {
+        self.outputs.len()
+    }
+
+    // Take the buffered outputs, leaving the buffer empty.
+    pub fn drain_outputs(&mut self) -> std::vec::Drain<'_, Word> {
+        self.outputs.drain(..)
+    }
+
+    // Queue |s| as a line of ASCII input, terminated by a newline.
+    pub fn push_ascii_line(&mut self, s: &str) {
+        for b in s.trim_end_matches('\n').bytes() {
+            self.push_input(b as Word);
+        }
+        self.push_input(10);
+    }
+
+    // As push_ascii_line, but refuse text with non-ASCII characters rather
+    // than queueing their UTF-8 bytes. Nothing is queued on error.
+    pub fn push_ascii(&mut self, line: &str) -> Result<(), CpuError> {
+        let line = line.trim_end_matches('\n');
+        if let Some(c) = line.chars().find(|c| !c.is_ascii()) {
+            return Err(CpuError::NonAscii(c));
+        }
+        self.push_ascii_line(line);
+        Ok(())
+    }
+
+    pub fn push_ascii_lines(&mut self, lines: &[&str]) -> Result<(), CpuError> {
+        if let Some(c) = lines.iter().flat_map(|l| l.chars()).find(|c| !c.is_ascii()) {
+            return Err(CpuError::NonAscii(c));
+        }
+        for line in lines {
+            self.push_ascii_line(line);
+        }
+        Ok(())
+    }
+
+    pub fn add_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn clear_breakpoint(&mut self, addr: usize) {
+        self.breakpoints.remove(&addr);
+    }
+
+    // Pause after any instruction that writes to |addr|.
+    pub fn watch(&mut self, addr: usize) {
+        self.watchpoints.insert(addr);
+    }
+
+    // Send every OUTPUT value to |tx| as well as buffering it, e.g. to feed
+    // another cpu's input. Each call adds another channel.
+    pub fn connect_output(&mut self, tx: Sender<Word>) {
+        self.output_tx.push(tx);
+    }
+
+    // Once the input queue is empty, read from |rx| instead of stdin. An
+    // empty channel falls back on on_eof, reading -1 unless it's set, as the
+    // network in day 23 expects.
+    pub fn set_input_receiver(&mut self, rx: Receiver<Word>) {
+        self.input_rx = Some(rx);
+    }
+
+    // Stream OUTPUT values to |f| as they are produced, alongside the
+    // usual buffering. Each call adds another hook.
+    pub fn on_output(&mut self, f: impl FnMut(Word) + 'static) {
+        self.output_hooks.push(Box::new(f));
+    }
+
+    // Route reads and writes to addresses in |range| to |device| instead
+    // of memory, e.g. a console region that prints what's written to it.
+    // The device sees the full address. Later mappings take precedence
+    // where ranges overlap.
+    pub fn map(&mut self, range: Range<usize>, device: impl Memory + 'static) {
+        self.devices.push((range, Box::new(device)));
+    }
+
+    // Writes that landed on an opcode while detect_self_mod was set, as
+    // (addr, old, new).
+    pub fn self_modifications(&self) -> &[(usize, Word, Word)] {
+        &self.self_mods
+    }
+
+    // Teach the cpu a new opcode between 10 and 98 taking |arity| (at most
+    // 3) parameters. |handler| gets the cpu, with ip already past the
+    // instruction, and the raw parameters, which value_of resolves.
+    pub fn register_op(&mut self, opcode: Word, arity: usize, handler: OpHandler) {
+        assert!((10..99).contains(&opcode), "opcode {} is reserved", opcode);
+        assert!(arity <= 3, "custom opcodes take at most 3 parameters");
+        self.custom_ops.insert(opcode, (arity, Some(handler)));
+    }
+
+    // The value a parameter reads as, e.g. from a custom opcode's handler.
+    pub fn value_of(&self, p: Parameter) -> Result<Word, CpuError> {
+        self.resolve(p)
+    }
+
+    // Whether the last INPUT came up empty, see InputMode::NonBlocking.
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    // Number of times each instruction has executed, by name.
+    pub fn op_counts(&self) -> HashMap<&'static str, u64> {
+        OP_NAMES
+            .iter()
+            .copied()
+            .zip(self.op_counts.iter().copied())
+            .collect()
+    }
+
+    // Time spent executing each instruction while profile was set, by
+    // name. Covers the whole instruction, including any wait on stdin.
+    pub fn timing(&self) -> HashMap<&'static str, Duration> {
+        OP_NAMES
+            .iter()
+            .copied()
+            .zip(self.op_times.iter().copied())
+            .collect()
+    }
+
+    // How many times each address was read as a parameter and written,
+    // as (reads, writes), while profile was set. Instruction fetches
+    // aren't counted.
+    pub fn access_stats(&self) -> HashMap<usize, (u64, u64)> {
+        self.access.clone()
+    }
+
+    // Opcode of the instruction at ip, without executing it, or None past
+    // the end of memory. Lets a scheduler see that a cpu is about to block
+    // on INPUT.
+    pub fn peek_next_opcode(&self) -> Option<Word> {
+        self.memory.get(self.ip).map(|word| word % 100)
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn rbase(&self) -> Word {
+        self.rbase
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Where the program halted, or None if it hasn't yet.
+    pub fn halt_ip(&self) -> Option<usize> {
+        self.halt_ip
+    }
+
+    // Dump the cpu as JSON so a long computation can be saved and picked
+    // up later with Cpu::restore.
+    pub fn snapshot(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn restore(s: &str) -> Result<Cpu, serde_json::Error> {
+        serde_json::from_str(s)
+    }
+
+    // Compact alternative to snapshot for large memories: ip, rbase and
+    // cycles followed by the initial program, memory, inputs and outputs,
+    // each prefixed with its length, then a byte saying whether memory is
+    // sparse and if so the count of sparse cells and each as an address
+    // and value. Everything is little-endian. Settings are not saved.
+    // Cells take the width of Word, so bytes only load into a build with
+    // the same `wide` setting.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&(self.ip as u64).to_le_bytes());
+        out.extend_from_slice(&self.rbase.to_le_bytes());
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        put_cells(&mut out, &self.initial);
+        put_cells(&mut out, &self.memory);
+        put_cells(&mut out, self.inputs.iter());
+        put_cells(&mut out, &self.outputs);
+        match &self.sparse {
+            Some(sparse) => {
+                let mut cells: Vec<_> = sparse.0.iter().collect();
+                cells.sort_unstable();
+                out.push(1);
+                out.extend_from_slice(&(cells.len() as u64).to_le_bytes());
+                for (&addr, v) in cells {
+                    out.extend_from_slice(&(addr as u64).to_le_bytes());
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+            }
+            None => out.push(0),
+        }
+        out
+    }
+
+    pub fn from_bytes(mut b: &[u8]) -> std::io::Result<Cpu> {
+        let ip = read_u64(&mut b)? as usize;
+        let rbase = read_word(&mut b)?;
+        let cycles = read_u64(&mut b)?;
+        let mut cpu = Cpu::new(Some(read_cells(&mut b)?));
+        cpu.memory = read_cells(&mut b)?;
+        cpu.inputs = read_cells(&mut b)?.into();
+        cpu.outputs = read_cells(&mut b)?;
+        let mut sparse = [0];
+        b.read_exact(&mut sparse)?;
+        if sparse[0] != 0 {
+            let len = read_u64(&mut b)?;
+            let cells = (0..len)
+                .map(|_| Ok((read_u64(&mut b)? as usize, read_word(&mut b)?)))
+                .collect::<std::io::Result<_>>()?;
+            cpu.sparse = Some(SparseMemory(cells));
+        }
+        if !b.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "trailing bytes after cpu state",
+            ));
+        }
+        cpu.ip = ip;
+        cpu.rbase = rbase;
+        cpu.cycles = cycles;
+        Ok(cpu)
+    }
+
+    // Build a vector of |cnt| parameters for the instruction |word| based
+    // on the flags in the opcode representing the parameter modes.
+    // Fails if the program ends before all |cnt| parameters.
+    fn pack_parameters(&mut self, word: Word, cnt: usize) -> Result<[Parameter; 3], CpuError> {
+        let vals = self
+            .memory
+            .get(self.ip..self.ip + cnt)
+            .ok_or(CpuError::OutOfBounds(self.memory.len()))?;
+        // Decoded in place rather than through decode_modes to keep the
+        // hot path free of allocations.
+        let mut params = [Parameter::Immediate(0); 3];
+        let mut flags = word.abs() / 100;
+        for (param, &val) in params.iter_mut().zip(vals) {
+            *param = match flags % 10 {
+                0 => Parameter::Position(val),
+                1 => Parameter::Immediate(val),
+                2 => Parameter::Relative(val),
+                m => return Err(CpuError::InvalidMode(m)),
+            };
+            flags /= 10;
+        }
+        if self.strict {
+            while flags != 0 {
+                if flags % 10 != 0 {
+                    return Err(CpuError::InvalidMode(flags % 10));
+                }
+                flags /= 10;
+            }
+        }
+        self.ip += cnt;
+        Ok(params)
+    }
+
+    // Grow memory with zeroes so that |addr| is a valid index.
+    fn ensure(&mut self, addr: usize) {
+        if addr >= self.memory.len() {
+            self.memory.resize(addr + 1, 0);
+        }
+    }
+
+    // Read a parameter for the executing instruction, counting the memory
+    // read when profiling.
+    fn unpack_parameter(&mut self, p: Parameter) -> Result<Word, CpuError> {
+        if self.profile {
+            let addr = match p {
+                Parameter::Immediate(_) => None,
+                Parameter::Position(x) => Some(addr(x)?),
+                Parameter::Relative(x) => Some(self.relative(x)?),
+            };
+            if let Some(addr) = addr {
+                self.access.entry(addr).or_default().0 += 1;
+            }
+        }
+        self.resolve(p)
+    }
+
+    fn resolve(&self, p: Parameter) -> Result<Word, CpuError> {
+        let addr = match p {
+            Parameter::Immediate(x) => return Ok(x),
+            Parameter::Position(x) => addr(x)?,
+            Parameter::Relative(x) => self.relative(x)?,
+        };
+        Ok(self.backing(addr).read(addr))
+    }
+
+    // The memory that holds |addr|: a mapped device, the program itself,
+    // or the sparse cells past its end when running sparse.
+    fn backing(&self, addr: usize) -> &dyn Memory {
+        if let Some((_, device)) = self.devices.iter().rev().find(|(r, _)| r.contains(&addr)) {
+            return device.as_ref();
+        }
+        match &self.sparse {
+            Some(sparse) if addr >= self.memory.len() => sparse,
+            _ => &self.memory,
+        }
+    }
+
+    fn backing_mut(&mut self, addr: usize) -> &mut dyn Memory {
+        if let Some(i) = self.devices.iter().rposition(|(r, _)| r.contains(&addr)) {
+            return self.devices[i].1.as_mut();
+        }
+        let len = self.memory.len();
+        match &mut self.sparse {
+            Some(sparse) if addr >= len => sparse,
+            _ => &mut self.memory,
+        }
+    }
+
+    // Resolve a parameter used as a write destination to the address it
+    // refers to. Writes are never immediate.
+    fn write_addr(&self, p: Parameter) -> Result<usize, CpuError> {
+        match p {
+            Parameter::Position(x) => addr(x),
+            Parameter::Relative(x) => self.relative(x),
+            Parameter::Immediate(_) => Err(CpuError::InvalidMode(1)),
+        }
+    }
+
+    // The address |x| cells from the relative base. A negative result is
+    // rejected by addr like any other negative address.
+    fn relative(&self, x: Word) -> Result<usize, CpuError> {
+        addr(self.rbase.checked_add(x).ok_or(CpuError::RelBaseOverflow)?)
+    }
+
+    // All memory writes go through here, growing memory to fit and
+    // noting any write to a watched address.
+    fn store(&mut self, addr: usize, val: Word) -> Result<(), CpuError> {
+        if addr >= self.max_memory
+            && addr >= self.memory.len()
+            && self.sparse.is_none()
+            && !self.devices.iter().any(|(r, _)| r.contains(&addr))
+        {
+            return Err(CpuError::MemoryLimitExceeded(addr));
+        }
+        if self.watchpoints.contains(&addr) {
+            self.watch_hit = Some((addr, self.backing(addr).read(addr), val));
+        }
+        if self.profile {
+            self.access.entry(addr).or_default().1 += 1;
+        }
+        if self.detect_self_mod && self.starts_instruction(addr) == Some(true) {
+            let old = self.backing(addr).read(addr);
+            self.self_mods.push((addr, old, val));
+        }
+        if self.undo_depth > 0 {
+            let old = self.backing(addr).read(addr);
+            if let Some(step) = self.undo_log.back_mut() {
+                step.writes.push((addr, old));
+            }
+        }
+        self.backing_mut(addr).write(addr, val);
+        Ok(())
+    }
+
+    fn fetch_and_decode(&mut self) -> Result<Instruction, CpuError> {
+        let word = *self
+            .memory
+            .get(self.ip)
+            .ok_or(CpuError::OutOfBounds(self.ip))?;
+        self.ip += 1;
+        let opcode = word % 100;
+        if !matches!(opcode, 1..=9 | 99) {
+            let n = match self.custom_ops.get(&opcode) {
+                Some(&(n, _)) => n,
+                None => return Err(CpuError::InvalidOpcode(opcode, self.ip - 1)),
+            };
+            if self.detect_self_mod || self.align_check {
+                self.mark_decoded(self.ip - 1, n);
+            }
+            let params = self.pack_parameters(word, n)?;
+            return Ok(Instruction::CUSTOM(opcode, n, params));
+        }
+        if self.detect_self_mod || self.align_check {
+            self.mark_decoded(self.ip - 1, arity(opcode));
+        }
+        let [a, b, c] = self.pack_parameters(word, arity(opcode))?;
+        let instruction = match opcode {
+            1 => Instruction::ADD([a, b, c]),
+            2 => Instruction::MUL([a, b, c]),
+            3 => Instruction::INPUT([a]),
+            4 => Instruction::OUTPUT([a]),
+            5 => Instruction::JUMP(true, [a, b]),
+            6 => Instruction::JUMP(false, [a, b]),
+            7 => Instruction::LESSTHAN([a, b, c]),
+            8 => Instruction::EQUALS([a, b, c]),
+            9 => Instruction::RELBASE([a]),
+            _ => Instruction::HALT,
+        };
+        Ok(instruction)
+    }
+
+    // Decode and execute the instruction at |ip|. Once HALT is reached
+    // |ip| is left on it so further steps stay halted.
+    pub fn step(&mut self) -> Result<RunState, CpuError> {
+        self.step_detailed().map(|(_, state)| state)
+    }
+
+    // As step, but also hand back the instruction that ran, e.g. for a
+    // debugger to show. None when the cpu stopped before executing
+    // anything, at a breakpoint or to wait for input.
+    pub fn step_detailed(&mut self) -> Result<(Option<Instruction>, RunState), CpuError> {
+        if self.ip >= self.memory.len() {
+            return Err(CpuError::RanOffEnd(self.ip));
+        }
+        if self.breakpoints.contains(&self.ip) && self.break_hit != Some(self.ip) {
+            self.break_hit = Some(self.ip);
+            return Ok((None, RunState::Breakpoint(self.ip)));
+        }
+        if self.max_cycles.is_some_and(|max| self.cycles >= max) {
+            return Ok((None, RunState::CycleLimitExceeded(self.ip)));
+        }
+        let start = self.ip;
+        let word = self.memory[start];
+        if word % 100 == 3
+            && self.inputs.is_empty()
+            && self.input_rx.is_none()
+            && self.default_input.is_none()
+            && self.input_mode == InputMode::Blocking
+            && !self.interactive
+        {
+            return Ok((None, RunState::NeedInput));
+        }
+        // Decode, and take INPUT's value, before committing to the
+        // instruction, so that stopping on EOF leaves no trace of it:
+        // nothing counted, traced or logged for undo, and no cycles spent.
+        // Leave ip on a faulting instruction so the fault can be reported
+        // against it, see trap.
+        let queued = !self.inputs.is_empty();
+        let decoded = self
+            .fetch_and_decode()
+            .and_then(|instruction| match instruction {
+                Instruction::INPUT(_) => Ok((instruction, self.read_input()?)),
+                _ => Ok((instruction, None)),
+            });
+        let (instruction, input) = match decoded {
+            Ok((Instruction::INPUT(_), None)) => {
+                self.ip = start;
+                return Ok((None, RunState::Halted));
+            }
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self.ip = start;
+                return Err(e);
+            }
+        };
+        // Only now is the breakpoint passed; pausing for input or at the
+        // cycle limit above leaves it to be resumed through.
+        self.break_hit = None;
+        if self.undo_depth > 0 {
+            if self.undo_log.len() >= self.undo_depth {
+                self.undo_log.pop_front();
+            }
+            self.undo_log.push_back(UndoStep {
+                ip: start,
+                rbase: self.rbase,
+                outputs: self.outputs.len(),
+                input: input.filter(|_| queued),
+                ..UndoStep::default()
+            });
+        }
+        let timer = if self.profile {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        let result = self.execute(start, word, instruction, input);
+        match (&result, timer) {
+            (Err(_), _) => {
+                self.ip = start;
+                if self.undo_depth > 0 {
+                    self.undo_log.pop_back();
+                }
+                // Give back a queued value so the INPUT can be retried.
+                if let Some(v) = input.filter(|_| queued) {
+                    self.inputs.push_front(v);
+                }
+            }
+            (Ok(_), Some(timer)) => {
+                if let Some(t) = self.op_times.get_mut((word % 100 % 99) as usize) {
+                    *t += timer.elapsed();
+                }
+            }
+            _ => {}
+        }
+        result
+    }
+
+    // Run |instruction|, decoded from |word| at |start| with ip already
+    // past it.
+    fn execute(
+        &mut self,
+        start: usize,
+        word: Word,
+        instruction: Instruction,
+        input: Option<Word>,
+    ) -> Result<(Option<Instruction>, RunState), CpuError> {
+        if !matches!(instruction, Instruction::CUSTOM(..)) {
+            self.op_counts[(word % 100 % 99) as usize] += 1;
+        }
+        self.cycles += 1;
+        self.trace(start, word % 100, &instruction);
+        let done = |state| Ok((Some(instruction), state));
+        match instruction {
+            Instruction::ADD(args) => self.op_add(args)?,
+            Instruction::MUL(args) => self.op_mul(args)?,
+            Instruction::INPUT(args) => {
+                self.op_input(args, input.expect("INPUT without a value read"))?
+            }
+            Instruction::OUTPUT(args) => {
+                let val = self.op_output(args)?;
+                if self.halt_on_output {
+                    return done(RunState::OutputReady(val));
+                }
+            }
+            Instruction::JUMP(test, args) => self.op_jump(test, args)?,
+            Instruction::LESSTHAN(args) => self.op_lessthan(args)?,
+            Instruction::EQUALS(args) => self.op_equals(args)?,
+            Instruction::RELBASE(args) => self.op_relbase(args)?,
+            Instruction::CUSTOM(opcode, n, args) => self.op_custom(opcode, &args[..n]),
+            Instruction::HALT => {
+                self.ip -= 1;
+                self.halt_ip = Some(self.ip);
+                return done(RunState::Halted);
+            }
+        }
+        if let Some((addr, old, new)) = self.watch_hit.take() {
+            return done(RunState::Watchpoint { addr, old, new });
+        }
+        done(RunState::Continue)
+    }
+
+    // Describe |e| along with the state of the cpu that raised it, e.g.
+    // for a panic message.
+    pub fn trap(&self, e: &CpuError) -> String {
+        let op = match self.peek(self.ip) % 100 {
+            opcode @ 1..=9 | opcode @ 99 => OP_NAMES[(opcode % 99) as usize],
+            _ => "???",
+        };
+        format!("{:?} at ip={}, rbase={}, op={}", e, self.ip, self.rbase, op)
+    }
+
+    // Log the instruction about to execute and record it in trace_sink.
+    fn trace(&mut self, ip: usize, opcode: Word, instruction: &Instruction) {
+        // Skip building the record when nobody will see it.
+        if self.trace_sink.is_none() && !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        if !self.trace_filter.is_empty() && !self.trace_filter.contains(&opcode) {
+            return;
+        }
+        let params = instruction
+            .params()
+            .iter()
+            .map(|&p| match p {
+                Parameter::Relative(x) => Parameter::Position(self.rbase.saturating_add(x)),
+                p => p,
+            })
+            .collect();
+        let trace = Trace {
+            cycle: self.cycles,
+            ip,
+            op: match instruction {
+                Instruction::CUSTOM(..) => "CUSTOM",
+                _ => OP_NAMES[(opcode % 99) as usize],
+            },
+            params,
+            rbase: self.rbase,
+        };
+        log::debug!(
+            "cycle={} ip={} op={} params={:?} rbase={}",
+            trace.cycle,
+            trace.ip,
+            trace.op,
+            trace.params,
+            trace.rbase
+        );
+        if let Some(sink) = &mut self.trace_sink {
+            sink.push(trace);
+        }
+    }
+
+    pub fn run(mut self) -> Result<Cpu, CpuError> {
+        self.resume()?;
+        Ok(self)
+    }
+
+    // Run until the program halts or pauses waiting for input. Resuming
+    // a paused cpu picks up at the instruction that paused it.
+    pub fn resume(&mut self) -> Result<RunState, CpuError> {
+        loop {
+            match self.step()? {
+                RunState::Continue => {}
+                state => return Ok(state),
+            }
+        }
+    }
+
+    // Step at most |n| times, stopping early on anything but Continue.
+    // Returns Continue if all |n| instructions ran.
+    pub fn run_n(&mut self, n: u64) -> Result<RunState, CpuError> {
+        for _ in 0..n {
+            match self.step()? {
+                RunState::Continue => {}
+                state => return Ok(state),
+            }
+        }
+        Ok(RunState::Continue)
+    }
+
+    // As resume, but give up with RunState::Timeout once |dur| has passed.
+    // The clock is only checked every 10k instructions.
+    pub fn run_timeout(&mut self, dur: Duration) -> Result<RunState, CpuError> {
+        let start = Instant::now();
+        loop {
+            for _ in 0..10_000 {
+                match self.step()? {
+                    RunState::Continue => {}
+                    state => return Ok(state),
+                }
+            }
+            if start.elapsed() >= dur {
+                return Ok(RunState::Timeout);
+            }
+        }
+    }
+
+    // Run until the next OUTPUT and return its value, leaving the cpu
+    // paused so a later call resumes where this one stopped. Returns None
+    // once the program halts or is waiting on input.
+    pub fn run_until_output(&mut self) -> Result<Option<Word>, CpuError> {
+        let produced = self.outputs.len();
+        loop {
+            let state = self.step()?;
+            if self.outputs.len() > produced {
+                return Ok(self.outputs.last().copied());
+            }
+            if state != RunState::Continue {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Run until |n| more outputs have been produced and return them, e.g. a
+    // whole day 23 packet. If the program stops first, whatever it did
+    // produce is returned, or None if nothing.
+    pub fn run_until_outputs(&mut self, n: usize) -> Result<Option<Vec<Word>>, CpuError> {
+        let produced = self.outputs.len();
+        while self.outputs.len() - produced < n {
+            match self.step()? {
+                RunState::Continue | RunState::OutputReady(_) => {}
+                _ => break,
+            }
+        }
+        match &self.outputs[produced..] {
+            [] => Ok(None),
+            batch => Ok(Some(batch.to_vec())),
+        }
+    }
+
+    // Run until the program is about to execute an INPUT with nothing
+    // queued, returning RunState::NeedInput with ip on that INPUT, so a
+    // driver can choose the next input from what it has seen so far. Other
+    // stops, such as halting, are returned as they happen.
+    pub fn run_until_input(&mut self) -> Result<RunState, CpuError> {
+        loop {
+            if self.peek_next_opcode() == Some(3)
+                && self.inputs.is_empty()
+                && self.input_rx.is_none()
+                && self.default_input.is_none()
+            {
+                return Ok(RunState::NeedInput);
+            }
+            match self.step()? {
+                RunState::Continue => {}
+                state => return Ok(state),
+            }
+        }
+    }
+
+    // Run until the program halts or needs input, rendering the outputs
+    // produced along the way as text. Values outside the ASCII range, such
+    // as a final puzzle answer, are returned separately.
+    pub fn run_ascii(&mut self) -> Result<(String, Vec<Word>), CpuError> {
+        let produced = self.outputs.len();
+        self.resume()?;
+        let mut text = String::new();
+        let mut other = Vec::new();
+        for &v in &self.outputs[produced..] {
+            match v {
+                0..=127 => text.push(v as u8 as char),
+                _ => other.push(v),
+            }
+        }
+        Ok((text, other))
+    }
+
+    // Debug the program from stdin: s(tep), c(ontinue), m(emory) ADDR,
+    // r(egisters), b(reak) ADDR and q(uit). Returns on quit or end of input.
+    pub fn monitor(&mut self) {
+        loop {
+            print!("mon> ");
+            stdout().flush().unwrap();
+            let mut line = String::new();
+            if stdin().read_line(&mut line).unwrap() == 0 {
+                return;
+            }
+            let result = match parse_command(&line) {
+                Some(MonitorCmd::Step) => self.step(),
+                Some(MonitorCmd::Continue) => self.resume(),
+                Some(MonitorCmd::Memory(addr)) => {
+                    println!("[{}] = {}", addr, self.peek(addr));
+                    continue;
+                }
+                Some(MonitorCmd::Registers) => {
+                    println!("ip={} rbase={} cycles={}", self.ip, self.rbase, self.cycles);
+                    continue;
+                }
+                Some(MonitorCmd::Break(addr)) => {
+                    self.add_breakpoint(addr);
+                    continue;
+                }
+                Some(MonitorCmd::Quit) => return,
+                None => {
+                    println!("commands: s, c, m ADDR, r, b ADDR, q");
+                    continue;
+                }
+            };
+            match result {
+                Ok(state) => {
+                    let next = disassemble(&self.memory[self.ip.min(self.memory.len())..]);
+                    println!(
+                        "{:?}, next {:04}: {}",
+                        state,
+                        self.ip,
+                        next.first().map_or("", |s| s)
+                    );
+                }
+                Err(e) => println!("cpu fault: {}", self.trap(&e)),
+            }
+        }
+    }
+
+    // Instruction implementations
+    fn op_add(&mut self, args: [Parameter; 3]) -> Result<(), CpuError> {
+        let dest = self.write_addr(args[2])?;
+        let (a, b) = (
+            self.unpack_parameter(args[0])?,
+            self.unpack_parameter(args[1])?,
+        );
+        let val = self.overflow(a.overflowing_add(b))?;
+        self.store(dest, val)?;
+        Ok(())
+    }
+
+    fn op_mul(&mut self, args: [Parameter; 3]) -> Result<(), CpuError> {
+        let dest = self.write_addr(args[2])?;
+        let (a, b) = (
+            self.unpack_parameter(args[0])?,
+            self.unpack_parameter(args[1])?,
+        );
+        let val = self.overflow(a.overflowing_mul(b))?;
+        self.store(dest, val)?;
+        Ok(())
+    }
+
+    // Apply on_overflow to the result of an overflowing_* operation.
+    fn overflow(&self, (val, overflowed): (Word, bool)) -> Result<Word, CpuError> {
+        match self.on_overflow {
+            Overflow::Error if overflowed => Err(CpuError::Overflow),
+            _ => Ok(val),
+        }
+    }
+
+    // Next value for INPUT, falling back on on_eof when there is none.
+    // Returns None if on_eof says to halt.
+    fn read_input(&mut self) -> Result<Option<Word>, CpuError> {
+        let mut eof = EofAction::Value(-1);
+        let val = if let Some(v) = self.inputs.pop_front() {
+            Some(v)
+        } else if let Some(rx) = &self.input_rx {
+            rx.try_recv().ok()
+        } else if self.default_input.is_some() {
+            self.default_input
+        } else if self.input_mode == InputMode::NonBlocking {
+            None
+        } else {
+            eof = EofAction::Error;
+            read_stdin()?
+        };
+        self.idle = val.is_none();
+        match (val, self.on_eof.unwrap_or(eof)) {
+            (Some(v), _) | (None, EofAction::Value(v)) => Ok(Some(v)),
+            (None, EofAction::Halt) => Ok(None),
+            (None, EofAction::Error) => Err(CpuError::UnexpectedEof),
+        }
+    }
+
+    fn op_input(&mut self, args: [Parameter; 1], val: Word) -> Result<(), CpuError> {
+        let dest = self.write_addr(args[0])?;
+        self.store(dest, val)?;
+        log::debug!("input [{}] = {}", dest, val);
+        Ok(())
+    }
+
+    // Every value goes to each destination in turn: echoed if verbose,
+    // buffered, sent on each connected channel, then passed to each hook,
+    // all in the order they were added.
+    fn op_output(&mut self, args: [Parameter; 1]) -> Result<Word, CpuError> {
+        let val = self.unpack_parameter(args[0])?;
+        if self.verbose {
+            print!("{}", self.echo(val));
+            stdout().flush().unwrap();
+        }
+        self.outputs.push(val);
+        for tx in &self.output_tx {
+            // A disconnected peer simply stops listening.
+            let _ = tx.send(val);
+        }
+        for hook in &mut self.output_hooks {
+            hook(val);
+        }
+        Ok(val)
+    }
+
+    // How an OUTPUT value is echoed when verbose.
+    fn echo(&self, val: Word) -> String {
+        match val {
+            0..=127 if self.ascii_output => char::from(val as u8).to_string(),
+            _ if self.ascii_output => format!("{}\n", val),
+            _ => format!("> {}\n", val),
+        }
+    }
+
+    fn op_jump(&mut self, test: bool, args: [Parameter; 2]) -> Result<(), CpuError> {
+        if (self.unpack_parameter(args[0])? != 0) == test {
+            self.ip = addr(self.unpack_parameter(args[1])?)?;
+            if self.align_check {
+                self.check_alignment();
+            }
+        }
+        Ok(())
+    }
+
+    // Warn if ip was just jumped into the middle of an instruction, see
+    // starts_instruction.
+    fn check_alignment(&mut self) {
+        if self.starts_instruction(self.ip) == Some(false) {
+            log::warn!("jump to {} lands mid-instruction", self.ip);
+        }
+    }
+
+    // For each address of the loaded program, whether an instruction starts
+    // there. Only the code for_each_instruction can walk, up to the first
+    // HALT, is covered; addresses past it are left out.
+    fn code_starts(&mut self) -> &[bool] {
+        let initial = &self.initial;
+        self.code_starts.get_or_insert_with(|| {
+            let mut starts = Vec::new();
+            for_each_instruction(initial, |ip, _, modes| {
+                starts.resize(ip + modes.len() + 1, false);
+                starts[ip] = true;
+            });
+            starts
+        })
+    }
+
+    // Record that an instruction with |n| parameters was decoded at |ip|.
+    // An address that has ever held an opcode stays marked as one.
+    fn mark_decoded(&mut self, ip: usize, n: usize) {
+        if self.decoded.len() <= ip + n {
+            self.decoded.resize(ip + n + 1, None);
+        }
+        self.decoded[ip] = Some(true);
+        for param in &mut self.decoded[ip + 1..=ip + n] {
+            param.get_or_insert(false);
+        }
+    }
+
+    // Whether an instruction starts at |addr|, going by what has been
+    // decoded there and otherwise by code_starts. None when neither
+    // covers it.
+    fn starts_instruction(&mut self, addr: usize) -> Option<bool> {
+        match self.decoded.get(addr) {
+            Some(&Some(start)) => Some(start),
+            _ => self.code_starts().get(addr).copied(),
+        }
+    }
+
+    fn op_lessthan(&mut self, args: [Parameter; 3]) -> Result<(), CpuError> {
+        let dest = self.write_addr(args[2])?;
+        let val = (self.unpack_parameter(args[0])? < self.unpack_parameter(args[1])?) as Word;
+        self.store(dest, val)?;
+        Ok(())
+    }
+
+    fn op_equals(&mut self, args: [Parameter; 3]) -> Result<(), CpuError> {
+        let dest = self.write_addr(args[2])?;
+        let val = (self.unpack_parameter(args[0])? == self.unpack_parameter(args[1])?) as Word;
+        self.store(dest, val)?;
+        Ok(())
+    }
+
+    fn op_custom(&mut self, opcode: Word, args: &[Parameter]) {
+        let handler = self.custom_ops.get_mut(&opcode).and_then(|(_, h)| h.take());
+        let mut handler = handler.expect("custom opcode run from its own handler");
+        handler(self, args);
+        if let Some((_, slot)) = self.custom_ops.get_mut(&opcode) {
+            *slot = Some(handler);
+        }
+    }
+
+    fn op_relbase(&mut self, args: [Parameter; 1]) -> Result<(), CpuError> {
+        let delta = self.unpack_parameter(args[0])?;
+        let old = self.rbase;
+        self.rbase = old.checked_add(delta).ok_or(CpuError::RelBaseOverflow)?;
+        log::debug!("rbase {} -> {}", old, self.rbase);
+        Ok(())
+    }
+}
+
+// Clones copy everything but the channel links, output hooks, mapped
+// devices and custom opcodes, which can't be shared.
+impl Clone for Cpu {
+    fn clone(&self) -> Cpu {
+        Cpu {
+            ip: self.ip,
+            rbase: self.rbase,
+            memory: self.memory.clone(),
+            sparse: self.sparse.clone(),
+            initial: self.initial.clone(),
+            inputs: self.inputs.clone(),
+            outputs: self.outputs.clone(),
+            verbose: self.verbose,
+            ascii_output: self.ascii_output,
+            strict: self.strict,
+            interactive: self.interactive,
+            input_mode: self.input_mode,
+            on_eof: self.on_eof,
+            on_overflow: self.on_overflow,
+            default_input: self.default_input,
+            halt_on_output: self.halt_on_output,
+            align_check: self.align_check,
+            detect_self_mod: self.detect_self_mod,
+            self_mods: self.self_mods.clone(),
+            code_starts: self.code_starts.clone(),
+            decoded: self.decoded.clone(),
+            idle: self.idle,
+            max_cycles: self.max_cycles,
+            max_memory: self.max_memory,
+            trace_sink: self.trace_sink.clone(),
+            trace_filter: self.trace_filter.clone(),
+            breakpoints: self.breakpoints.clone(),
+            break_hit: self.break_hit,
+            watchpoints: self.watchpoints.clone(),
+            watch_hit: self.watch_hit,
+            output_tx: Vec::new(),
+            input_rx: None,
+            output_hooks: Vec::new(),
+            devices: Vec::new(),
+            custom_ops: HashMap::new(),
+            op_counts: self.op_counts,
+            profile: self.profile,
+            undo_depth: self.undo_depth,
+            undo_log: self.undo_log.clone(),
+            op_times: self.op_times,
+            access: self.access.clone(),
+            cycles: self.cycles,
+            halt_ip: self.halt_ip,
+        }
+    }
+}
+
+// Cpus are equal when their machine state is: registers, memory and the
+// pending inputs and outputs. Settings and counters are ignored.
+impl PartialEq for Cpu {
+    fn eq(&self, other: &Cpu) -> bool {
+        self.ip == other.ip
+            && self.rbase == other.rbase
+            && self.memory == other.memory
+            && self.sparse == other.sparse
+            && self.inputs == other.inputs
+            && self.outputs == other.outputs
+    }
+}
+
+impl fmt::Debug for Cpu {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cpu")
+            .field("ip", &self.ip)
+            .field("rbase", &self.rbase)
+            .field("memory", &self.memory)
+            .field("sparse", &self.sparse)
+            .field("inputs", &self.inputs)
+            .field("outputs", &self.outputs)
+            .field("cycles", &self.cycles)
+            .finish()
+    }
+}
+
+pub struct CpuBuilder {
+    program: Option<Vec<Word>>,
+    inputs: Vec<Word>,
+    verbose: bool,
+    interactive: bool,
+    memory_size: usize,
+    sparse: bool,
+    max_cycles: Option<u64>,
+    max_memory: usize,
+    on_eof: Option<EofAction>,
+    on_overflow: Overflow,
+    default_input: Option<Word>,
+    align_check: bool,
+    strict: bool,
+    trace_filter: HashSet<Word>,
+}
+
+impl Default for CpuBuilder {
+    fn default() -> CpuBuilder {
+        CpuBuilder {
+            program: None,
+            inputs: Vec::new(),
+            verbose: true,
+            interactive: true,
+            memory_size: 0,
+            sparse: false,
+            max_cycles: None,
+            max_memory: MAX_MEMORY,
+            on_eof: None,
+            on_overflow: Overflow::Wrap,
+            default_input: None,
+            align_check: false,
+            strict: false,
+            trace_filter: HashSet::new(),
+        }
+    }
+}
+
+impl CpuBuilder {
+    pub fn new() -> CpuBuilder {
+        CpuBuilder::default()
+    }
+
+    // Without a program the cpu loads INPUT_FILE, as with Cpu::new(None).
+    pub fn program(mut self, program: Vec<Word>) -> CpuBuilder {
+        self.program = Some(program);
+        self
+    }
+
+    pub fn input(mut self, v: Word) -> CpuBuilder {
+        self.inputs.push(v);
+        self
+    }
+
+    pub fn inputs(mut self, vals: &[Word]) -> CpuBuilder {
+        self.inputs.extend_from_slice(vals);
+        self
+    }
+
+    pub fn verbose(mut self, verbose: bool) -> CpuBuilder {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn interactive(mut self, interactive: bool) -> CpuBuilder {
+        self.interactive = interactive;
+        self
+    }
+
+    // No console output or stdin prompts at all, e.g. for benchmarking.
+    // Input that runs dry pauses the cpu instead.
+    pub fn quiet(self) -> CpuBuilder {
+        self.verbose(false).interactive(false)
+    }
+
+    // Pre-size memory to at least |n| cells. Programs longer than this are
+    // never truncated, and memory still grows on demand while running.
+    pub fn memory_size(mut self, n: usize) -> CpuBuilder {
+        self.memory_size = n;
+        self
+    }
+
+    // Keep cells past the end of the program in a SparseMemory rather than
+    // growing memory to reach them.
+    pub fn sparse_memory(mut self, sparse: bool) -> CpuBuilder {
+        self.sparse = sparse;
+        self
+    }
+
+    pub fn max_cycles(mut self, n: u64) -> CpuBuilder {
+        self.max_cycles = Some(n);
+        self
+    }
+
+    pub fn max_memory(mut self, n: usize) -> CpuBuilder {
+        self.max_memory = n;
+        self
+    }
+
+    pub fn on_eof(mut self, action: EofAction) -> CpuBuilder {
+        self.on_eof = Some(action);
+        self
+    }
+
+    pub fn on_overflow(mut self, policy: Overflow) -> CpuBuilder {
+        self.on_overflow = policy;
+        self
+    }
+
+    pub fn default_input(mut self, v: Word) -> CpuBuilder {
+        self.default_input = Some(v);
+        self
+    }
+
+    pub fn align_check(mut self, check: bool) -> CpuBuilder {
+        self.align_check = check;
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> CpuBuilder {
+        self.strict = strict;
+        self
+    }
+
+    // Trace only instructions with these opcodes, e.g. &[3, 4] for just
+    // the I/O.
+    pub fn trace_filter(mut self, opcodes: &[Word]) -> CpuBuilder {
+        self.trace_filter = opcodes.iter().copied().collect();
+        self
+    }
+
+    pub fn build(self) -> Cpu {
+        let mut cpu = Cpu::new(self.program);
+        cpu.verbose = self.verbose;
+        cpu.interactive = self.interactive;
+        cpu.max_cycles = self.max_cycles;
+        cpu.max_memory = self.max_memory;
+        cpu.on_eof = self.on_eof;
+        cpu.on_overflow = self.on_overflow;
+        cpu.default_input = self.default_input;
+        cpu.align_check = self.align_check;
+        cpu.strict = self.strict;
+        cpu.trace_filter = self.trace_filter;
+        for v in self.inputs {
+            cpu.push_input(v);
+        }
+        if self.memory_size > 0 {
+            cpu.ensure(self.memory_size - 1);
+        }
+        if self.sparse {
+            cpu.sparse = Some(SparseMemory::default());
+        }
+        cpu
+    }
+}
+
+// A program that counts cell 20 down from |n| to zero and halts, taking
+// 2n + 1 instructions with no input or output, e.g. to benchmark the cpu.
+pub fn countdown(n: Word) -> Vec<Word> {
+    let mut prog = vec![1001, 20, -1, 20, 1005, 20, 0, 99];
+    prog.resize(21, 0);
+    prog[20] = n;
+    prog
+}
+
+// Run |program| quietly with |inputs| queued until it halts or wants more
+// input, returning the cpu to inspect. Panics if the cpu faults.
+pub fn execute(program: Vec<Word>, inputs: &[Word]) -> Cpu {
+    let mut cpu = CpuBuilder::new()
+        .program(program)
+        .inputs(inputs)
+        .quiet()
+        .build();
+    if let Err(e) = cpu.resume() {
+        panic!("cpu fault: {}", cpu.trap(&e));
+    }
+    cpu
+}
+
+// As execute, but returning just what the program output.
+pub fn run_collect(program: Vec<Word>, inputs: &[Word]) -> Vec<Word> {
+    execute(program, inputs).outputs
+}
+
+// Day 5 and 9 diagnostics output a zero for each test that passed, then
+// the diagnostic code. Returns that code, or every output if any test
+// failed or nothing was output, which points at a bug in the cpu.
+pub fn diagnostic_code(outputs: &[Word]) -> Result<Word, Vec<Word>> {
+    match outputs.split_last() {
+        Some((&code, tests)) if tests.iter().all(|&v| v == 0) => Ok(code),
+        _ => Err(outputs.to_vec()),
+    }
+}
+
+// Day 7 amplifier chain: run one copy of |program| per phase setting, each
+// given its phase then the previous amplifier's output, starting from
+// |initial|. Returns the last amplifier's output.
+pub fn amplify(program: &[Word], phases: &[Word], initial: Word) -> Word {
+    phases.iter().fold(initial, |signal, &phase| {
+        *run_collect(program.to_vec(), &[phase, signal])
+            .last()
+            .expect("amplifier produced no output")
+    })
+}
+
+// As amplify, but the last amplifier feeds back into the first until the
+// amplifiers halt. Returns the final signal sent to the thrusters.
+pub fn amplify_loop(program: &[Word], phases: &[Word], initial: Word) -> Word {
+    let mut amps: Vec<Cpu> = phases
+        .iter()
+        .map(|&phase| {
+            CpuBuilder::new()
+                .program(program.to_vec())
+                .input(phase)
+                .quiet()
+                .build()
+        })
+        .collect();
+
+    let mut signal = initial;
+    loop {
+        for amp in amps.iter_mut() {
+            amp.push_input(signal);
+            match amp.run_until_output() {
+                Ok(Some(v)) => signal = v,
+                Ok(None) => return signal,
+                Err(e) => panic!("cpu fault: {}", amp.trap(&e)),
+            }
+        }
+    }
+}
+
+// Day 2 search: try every noun and verb from 0 to 99 in addresses 1 and 2,
+// returning the first pair that leaves |target| in address 0.
+pub fn find_noun_verb(program: &[Word], target: Word) -> Option<(Word, Word)> {
+    let mut cpu = CpuBuilder::new().program(program.to_vec()).quiet().build();
+    for noun in 0..100 {
+        for verb in 0..100 {
+            cpu.reset();
+            cpu.poke(1, noun);
+            cpu.poke(2, verb);
+            if cpu.resume() == Ok(RunState::Halted) && cpu.peek(0) == target {
+                return Some((noun, verb));
+            }
+        }
+    }
+    None
+}
+
+// Cells that differ between two memory images as (addr, old, new). Cells
+// past the end of the shorter image count as 0.
+pub fn memory_diff(before: &[Word], after: &[Word]) -> Vec<(usize, Word, Word)> {
+    let cell = |mem: &[Word], addr| mem.get(addr).copied().unwrap_or(0);
+    (0..before.len().max(after.len()))
+        .map(|addr| (addr, cell(before, addr), cell(after, addr)))
+        .filter(|(_, old, new)| old != new)
+        .collect()
+}
+
+// Prompt for and read a number from stdin, or None at its end.
+fn read_stdin() -> Result<Option<Word>, CpuError> {
+    let bad = |e: std::io::Error| CpuError::BadInput(e.to_string());
+    print!("$ ");
+    stdout().flush().map_err(bad)?;
+    let mut buffer = String::new();
+    if stdin().read_line(&mut buffer).map_err(bad)? == 0 {
+        return Ok(None);
+    }
+    match buffer.trim().parse() {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => Err(CpuError::BadInput(format!("{:?}: {}", buffer.trim(), e))),
+    }
+}
+
+// Helpers for Cpu::to_bytes and Cpu::from_bytes.
+fn put_cells<'a>(out: &mut Vec<u8>, cells: impl IntoIterator<Item = &'a Word>) {
+    let cells: Vec<&Word> = cells.into_iter().collect();
+    out.extend_from_slice(&(cells.len() as u64).to_le_bytes());
+    for v in cells {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+}
+
+fn read_u64(r: &mut &[u8]) -> std::io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_word(r: &mut &[u8]) -> std::io::Result<Word> {
+    let mut buf = [0; std::mem::size_of::<Word>()];
+    r.read_exact(&mut buf)?;
+    Ok(Word::from_le_bytes(buf))
+}
+
+fn read_cells(r: &mut &[u8]) -> std::io::Result<Vec<Word>> {
+    let len = read_u64(r)?;
+    (0..len).map(|_| read_word(r)).collect()
+}
+
+// Walk |mem| without executing it, calling |f| with the address, opcode
+// and parameter modes of each instruction. Stops after the first HALT or at
+// anything that doesn't decode.
+pub fn for_each_instruction(mem: &[Word], mut f: impl FnMut(usize, Word, &[u8])) {
+    let mut ip = 0;
+    while ip < mem.len() {
+        let opcode = mem[ip] % 100;
+        if !matches!(opcode, 1..=9 | 99) {
+            break;
+        }
+        let cnt = arity(opcode);
+        if ip + cnt >= mem.len() {
+            break;
+        }
+        let modes = decode_modes(mem[ip], cnt);
+        if modes.iter().any(|&m| m > 2) {
+            break;
+        }
+        f(ip, opcode, &modes);
+        if opcode == 99 {
+            break;
+        }
+        ip += cnt + 1;
+    }
+}
+
+// Decode |mem| into one line of assembly per instruction, as far as
+// for_each_instruction gets.
+pub fn disassemble(mem: &[Word]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for_each_instruction(mem, |ip, opcode, modes| {
+        let (name, writes) = match opcode {
+            1 => ("ADD", true),
+            2 => ("MUL", true),
+            3 => ("INPUT", true),
+            4 => ("OUTPUT", false),
+            5 => ("JNZ", false),
+            6 => ("JZ", false),
+            7 => ("LESSTHAN", true),
+            8 => ("EQUALS", true),
+            9 => ("RELBASE", false),
+            _ => ("HALT", false),
+        };
+        let mut line = name.to_string();
+        for (i, &mode) in modes.iter().enumerate() {
+            let val = mem[ip + i + 1];
+            let param = match mode {
+                0 => Parameter::Position(val),
+                1 => Parameter::Immediate(val),
+                _ => Parameter::Relative(val),
+            };
+            if writes && i == modes.len() - 1 {
+                line.push_str(" ->");
+            }
+            line.push_str(&format!(" {:?}", param));
+        }
+        lines.push(line);
+    });
+    lines
+}
+
+// Entries of disassemble_json.
+#[derive(Serialize)]
+struct JsonInstruction {
+    ip: usize,
+    opcode: Word,
+    name: &'static str,
+    params: Vec<JsonParam>,
+    width: usize,
+}
+
+#[derive(Serialize)]
+struct JsonParam {
+    mode: u8,
+    value: Word,
+}
+
+// As disassemble, but as a JSON array of instructions for other tools,
+// e.g. {"ip":0,"opcode":4,"name":"OUTPUT","params":[{"mode":1,"value":5}],
+// "width":2}. Modes are the raw digits: 0 position, 1 immediate, 2
+// relative.
+pub fn disassemble_json(mem: &[Word]) -> String {
+    let mut instructions = Vec::new();
+    for_each_instruction(mem, |ip, opcode, modes| {
+        let params = modes
+            .iter()
+            .enumerate()
+            .map(|(i, &mode)| JsonParam {
+                mode,
+                value: mem[ip + i + 1],
+            })
+            .collect();
+        instructions.push(JsonInstruction {
+            ip,
+            opcode,
+            name: OP_NAMES[(opcode % 99) as usize],
+            params,
+            width: modes.len() + 1,
+        });
+    });
+    serde_json::to_string(&instructions).unwrap()
+}
+
+// Opcode and parameter count for an assembler mnemonic, accepting the
+// disassembler's names as well as short forms.
+fn opcode_for(mnemonic: &str) -> Option<(Word, usize)> {
+    let op = match mnemonic.to_ascii_lowercase().as_str() {
+        "add" => (1, 3),
+        "mul" => (2, 3),
+        "in" | "input" => (3, 1),
+        "out" | "output" => (4, 1),
+        "jnz" => (5, 2),
+        "jz" => (6, 2),
+        "lt" | "lessthan" => (7, 3),
+        "eq" | "equals" => (8, 3),
+        "rb" | "relbase" => (9, 1),
+        "halt" => (99, 0),
+        _ => return None,
+    };
+    Some(op)
+}
+
+// Assemble a program written one instruction to a line, e.g.
+// `mul #2 5 -> 6` or `jnz r1 @loop`. Parameters are positions unless
+// marked # for immediate or r for relative, and -> is only there to be
+// read. `name:` labels the address of what follows it on the line, and
+// @name stands for that address as an immediate, as does @20 for address
+// 20 itself. `data 1 2 3` lays down
+// raw values. Anything after // is ignored. Panics, naming the line, on
+// anything it can't assemble; it's meant for writing tests by hand.
+pub fn assemble(src: &str) -> Vec<Word> {
+    // Lay out addresses and collect labels first, so later labels can be
+    // used before they're defined.
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+    let mut addr = 0;
+    for (n, line) in src.lines().enumerate() {
+        let mut line = line.split("//").next().unwrap_or("").trim();
+        if let Some(i) = line.find(':') {
+            let label = line[..i].trim();
+            if labels.insert(label.to_string(), addr).is_some() {
+                panic!("line {}: label {:?} defined twice", n + 1, label);
+            }
+            line = line[i + 1..].trim();
+        }
+        let mut fields = line.split_whitespace().filter(|&f| f != "->");
+        let mnemonic = match fields.next() {
+            Some(mnemonic) => mnemonic,
+            None => continue,
+        };
+        let args: Vec<&str> = fields.collect();
+        let opcode = if mnemonic == "data" {
+            None
+        } else {
+            let (opcode, arity) = opcode_for(mnemonic)
+                .unwrap_or_else(|| panic!("line {}: unknown instruction {:?}", n + 1, mnemonic));
+            if args.len() != arity {
+                panic!(
+                    "line {}: {} takes {} parameters, not {}",
+                    n + 1,
+                    mnemonic,
+                    arity,
+                    args.len()
+                );
+            }
+            Some(opcode)
+        };
+        addr += args.len() + opcode.map_or(0, |_| 1);
+        lines.push((n + 1, opcode, args));
+    }
+
+    // Resolve a parameter to its mode digit and value.
+    let operand = |n: usize, field: &str| -> (Word, Word) {
+        let (mode, rest) = if let Some(rest) = field.strip_prefix('#') {
+            (1, rest)
+        } else if let Some(rest) = field.strip_prefix('r') {
+            (2, rest)
+        } else {
+            (0, field)
+        };
+        match rest.strip_prefix('@') {
+            Some(label) => match (labels.get(label), label.parse::<usize>()) {
+                (Some(&addr), _) | (None, Ok(addr)) => (mode.max(1), addr as Word),
+                (None, Err(_)) => panic!("line {}: unknown label {:?}", n, label),
+            },
+            None => match rest.parse() {
+                Ok(v) => (mode, v),
+                Err(_) => panic!("line {}: bad parameter {:?}", n, field),
+            },
+        }
+    };
+
+    let mut program = Vec::with_capacity(addr);
+    for (n, opcode, args) in lines {
+        let params: Vec<(Word, Word)> = args.iter().map(|a| operand(n, a)).collect();
+        match opcode {
+            Some(opcode) => {
+                let modes = params
+                    .iter()
+                    .rev()
+                    .fold(0, |acc, &(mode, _)| acc * 10 + mode);
+                program.push(opcode + modes * 100);
+                program.extend(params.iter().map(|&(_, v)| v));
+            }
+            None => program.extend(params.iter().map(|&(_, v)| v)),
+        }
+    }
+    program
+}
+
+// Parse a comma separated program. Fields are trimmed and empty ones,
+// such as after a trailing comma, are skipped. The program may be split
+// over several lines, with anything after // on a line ignored.
+pub fn parse_program(src: &str) -> Result<Vec<Word>, ParseIntError> {
+    parse_fields(src).map_err(|(_, e)| e)
+}
+
+// As parse_program, but also hands back the field that failed to parse.
+fn parse_fields(src: &str) -> Result<Vec<Word>, (String, ParseIntError)> {
+    src.lines()
+        .map(|line| line.split("//").next().unwrap_or(""))
+        .flat_map(|line| line.split(','))
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| field.parse().map_err(|e| (field.to_string(), e)))
+        .collect()
+}
+
+// Load the program at |path|, or from stdin if the path is "-".
+pub fn process_input(path: &Path) -> std::io::Result<Vec<Word>> {
+    let src = if path == Path::new("-") {
+        let mut src = String::new();
+        stdin().read_to_string(&mut src)?;
+        src
+    } else {
+        std::fs::read_to_string(path)?
+    };
+    parse_fields(&src).map_err(|(field, e)| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("bad value {:?}: {}", field, e),
+        )
+    })
+}
+
+// Load the program at |path| and run it quietly with |inputs| queued until
+// it halts or wants more input, returning everything it output.
+pub fn run_file(path: &Path, inputs: &[Word]) -> Result<Vec<Word>, CpuError> {
+    let program = process_input(path).map_err(|e| CpuError::Load(e.to_string()))?;
+    let mut cpu = CpuBuilder::new()
+        .program(program)
+        .inputs(inputs)
+        .quiet()
+        .build();
+    cpu.resume()?;
+    Ok(cpu.outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    // Collects log messages from threads that ask for them with
+    // capture_logs, leaving logging off everywhere else.
+    struct CaptureLog;
+
+    thread_local! {
+        static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    impl log::Log for CaptureLog {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            CAPTURED.with(|c| c.borrow().is_some())
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED.with(|c| {
+                if let Some(lines) = c.borrow_mut().as_mut() {
+                    lines.push(record.args().to_string());
+                }
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLog = CaptureLog;
+
+    // Run |f| and return the messages it logged on this thread.
+    fn capture_logs(f: impl FnOnce()) -> Vec<String> {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Debug);
+        }
+        CAPTURED.with(|c| *c.borrow_mut() = Some(Vec::new()));
+        f();
+        CAPTURED.with(|c| c.borrow_mut().take().unwrap())
+    }
+
+    // Run |program| quietly with |inputs| and report what it output and how
+    // it stopped, for comparing against recorded results.
+    fn diff_run(program: &[Word], inputs: &[Word]) -> (Vec<Word>, Result<RunState, CpuError>) {
+        let mut cpu = CpuBuilder::new()
+            .program(program.to_vec())
+            .inputs(inputs)
+            .quiet()
+            .build();
+        let state = cpu.resume();
+        (cpu.outputs, state)
+    }
+
+    // Define a test that runs a program quietly to HALT and checks its
+    // outputs, and optionally memory afterwards:
+    //
+    //     intcode_test!(name: [program], inputs [..] => [outputs], memory { addr => val });
+    macro_rules! intcode_test {
+        (
+            $name:ident: [$($prog:expr),* $(,)?]
+            $(, inputs [$($input:expr),*])?
+            => [$($out:expr),*]
+            $(, memory { $($addr:expr => $val:expr),* $(,)? })?
+        ) => {
+            #[test]
+            fn $name() {
+                let mut cpu = CpuBuilder::new().program(vec![$($prog),*]).quiet().build();
+                $($(cpu.push_input($input);)*)?
+                assert_eq!(cpu.resume(), Ok(RunState::Halted));
+                let expected: &[Word] = &[$($out),*];
+                assert_eq!(cpu.outputs(), expected);
+                $($(assert_eq!(cpu.peek($addr), $val, "memory[{}]", $addr);)*)?
+            }
+        };
+    }
+
+    intcode_test!(day2_add: [1, 0, 0, 0, 99] => [], memory { 0 => 2 });
+    intcode_test!(day2_mul: [2, 4, 4, 5, 99, 0] => [], memory { 5 => 9801 });
+    intcode_test!(
+        day2_program: [1, 9, 10, 3, 2, 3, 11, 0, 99, 30, 40, 50] => [],
+        memory { 0 => 3500, 3 => 70 }
+    );
+    intcode_test!(day5_echo: [3, 0, 4, 0, 99], inputs [-17] => [-17]);
+    intcode_test!(day5_eq8_hit: [3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8], inputs [8] => [1]);
+    intcode_test!(day5_eq8_miss: [3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8], inputs [7] => [0]);
+
+    // Day 5 comparison and jump examples, in both position and immediate
+    // mode.
+    intcode_test!(day5_lt8_position_below: [3, 9, 7, 9, 10, 9, 4, 9, 99, -1, 8], inputs [7] => [1]);
+    intcode_test!(day5_lt8_position_equal: [3, 9, 7, 9, 10, 9, 4, 9, 99, -1, 8], inputs [8] => [0]);
+    intcode_test!(day5_eq8_immediate_hit: [3, 3, 1108, -1, 8, 3, 4, 3, 99], inputs [8] => [1]);
+    intcode_test!(day5_eq8_immediate_miss: [3, 3, 1108, -1, 8, 3, 4, 3, 99], inputs [9] => [0]);
+    intcode_test!(day5_lt8_immediate_below: [3, 3, 1107, -1, 8, 3, 4, 3, 99], inputs [-3] => [1]);
+    intcode_test!(day5_lt8_immediate_above: [3, 3, 1107, -1, 8, 3, 4, 3, 99], inputs [9] => [0]);
+    intcode_test!(
+        day5_jump_position_zero: [3, 12, 6, 12, 15, 1, 13, 14, 13, 4, 13, 99, -1, 0, 1, 9],
+        inputs [0] => [0]
+    );
+    intcode_test!(
+        day5_jump_position_nonzero: [3, 12, 6, 12, 15, 1, 13, 14, 13, 4, 13, 99, -1, 0, 1, 9],
+        inputs [5] => [1]
+    );
+    intcode_test!(
+        day5_jump_immediate_zero: [3, 3, 1105, -1, 9, 1101, 0, 0, 12, 4, 12, 99, 1],
+        inputs [0] => [0]
+    );
+    intcode_test!(
+        day5_jump_immediate_nonzero: [3, 3, 1105, -1, 9, 1101, 0, 0, 12, 4, 12, 99, 1],
+        inputs [-2] => [1]
+    );
+
+    // The larger example outputs 999 below 8, 1000 at 8 and 1001 above.
+    macro_rules! day5_cmp8 {
+        ($name:ident, $input:expr => $out:expr) => {
+            intcode_test!(
+                $name: [
+                    3, 21, 1008, 21, 8, 20, 1005, 20, 22, 107, 8, 21, 20, 1006, 20, 31, 1106, 0,
+                    36, 98, 0, 0, 1002, 21, 125, 20, 4, 20, 1105, 1, 46, 104, 999, 1105, 1, 46,
+                    1101, 1000, 1, 20, 4, 20, 1105, 1, 46, 98, 99,
+                ],
+                inputs [$input] => [$out]
+            );
+        };
+    }
+
+    day5_cmp8!(day5_cmp8_below, 7 => 999);
+    day5_cmp8!(day5_cmp8_equal, 8 => 1000);
+    day5_cmp8!(day5_cmp8_above, 9 => 1001);
+
+    #[test]
+    fn example1() {
+        {
+            let cpu = Cpu::new(Some(vec![1101, 100, -1, 4, 0])).run().unwrap();
+            assert_eq!(cpu.memory[4], 99);
+        }
+        {
+            let cpu = Cpu::new(Some(vec![1002, 4, 3, 4, 33])).run().unwrap();
+            assert_eq!(cpu.memory[4], 99);
+        }
+    }
+
+    #[test]
+    fn execute_in_one_call() {
+        // Day 9: the quine, reading back through the relative base.
+        let quine = vec![
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        let cpu = execute(quine.clone(), &[]);
+        assert_eq!(cpu.outputs(), &quine[..]);
+        assert_eq!(cpu.halt_ip(), Some(15));
+        assert_eq!((cpu.rbase(), cpu.peek(100)), (16, 16));
+
+        let cpu = execute(vec![3, 7, 1002, 7, 2, 7, 99], &[21]);
+        assert_eq!(cpu.memory[7], 42);
+        let cpu = execute(vec![3, 0, 99], &[]);
+        assert_eq!(cpu.ip(), 0);
+    }
+
+    #[test]
+    fn relative_dest() {
+        // Set rbase to 10 then store 3 + 4 relative to it.
+        let cpu = Cpu::new(Some(vec![109, 10, 21101, 3, 4, 2, 99]))
+            .run()
+            .unwrap();
+        assert_eq!(cpu.memory[12], 7);
+        assert_eq!(cpu.memory[2], 21101);
+    }
+
+    #[test]
+    fn jump_alignment() {
+        // Jump to 4, the first parameter of the ADD, which holds 99 and so
+        // halts when executed.
+        let prog = vec![1105, 1, 4, 1101, 99, 0, 0, 99];
+        let run = |check| {
+            capture_logs(|| {
+                let cpu = CpuBuilder::new()
+                    .program(prog.clone())
+                    .quiet()
+                    .align_check(check)
+                    .build();
+                cpu.run().unwrap();
+            })
+        };
+        assert!(run(true).contains(&"jump to 4 lands mid-instruction".to_string()));
+        assert!(!run(false).iter().any(|l| l.contains("mid-instruction")));
+
+        // Past the first HALT: jump over it to an ADD, then back into the
+        // ADD's second parameter, which holds 99.
+        let logs = capture_logs(|| {
+            let cpu = CpuBuilder::new()
+                .program(vec![1105, 1, 4, 99, 1101, 0, 99, 11, 1105, 1, 6, 0])
+                .quiet()
+                .align_check(true)
+                .build();
+            cpu.run().unwrap();
+        });
+        assert!(logs.contains(&"jump to 6 lands mid-instruction".to_string()));
+        assert!(!logs.iter().any(|l| l.contains("jump to 4")));
+    }
+
+    #[test]
+    fn self_modifying() {
+        // Turn the MUL at 4 into an ADD, and write to its parameter too.
+        let prog = vec![1101, 0, 1, 4, 2, 7, 7, 7, 1101, 0, 3, 5, 99];
+        let mut cpu = CpuBuilder::new().program(prog.clone()).quiet().build();
+        cpu.detect_self_mod = true;
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.peek(7), 14);
+        assert_eq!(cpu.self_modifications(), [(4, 2, 1)]);
+
+        let cpu = CpuBuilder::new().program(prog).quiet().build();
+        let cpu = cpu.run().unwrap();
+        assert!(cpu.self_modifications().is_empty());
+
+        // Past the first HALT only code that has run is known: jump over
+        // the HALT at 3 to an ADD that overwrites its own opcode.
+        let prog = vec![1105, 1, 4, 99, 1101, 0, 1, 4, 99];
+        let mut cpu = CpuBuilder::new().program(prog).quiet().build();
+        cpu.detect_self_mod = true;
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.self_modifications(), [(4, 1101, 1)]);
+    }
+
+    #[test]
+    fn rbase_logged() {
+        let logs = capture_logs(|| {
+            let cpu = Cpu::new(Some(vec![109, 7, 109, -3, 99]));
+            cpu.run().unwrap();
+        });
+        assert!(logs.contains(&"rbase 0 -> 7".to_string()));
+        assert!(logs.contains(&"rbase 7 -> 4".to_string()));
+    }
+
+    #[test]
+    fn arithmetic_overflow() {
+        // [0] = MAX * 3, then [0] += MAX.
+        let prog = vec![1102, Word::MAX, 3, 0, 1001, 0, Word::MAX, 0, 99];
+        let cpu = CpuBuilder::new().program(prog.clone()).quiet().build();
+        let cpu = cpu.run().unwrap();
+        assert_eq!(
+            cpu.memory[0],
+            Word::MAX.wrapping_mul(3).wrapping_add(Word::MAX)
+        );
+        assert_eq!(cpu.memory[0], -4);
+
+        let mut cpu = CpuBuilder::new()
+            .program(prog)
+            .quiet()
+            .on_overflow(Overflow::Error)
+            .build();
+        assert_eq!(cpu.resume(), Err(CpuError::Overflow));
+        assert_eq!(cpu.ip(), 0);
+
+        // Overflowing in the negative direction is caught too.
+        let cpu = CpuBuilder::new()
+            .program(vec![1101, Word::MIN, -1, 0, 99])
+            .quiet()
+            .on_overflow(Overflow::Error)
+            .build();
+        assert_eq!(cpu.run().err(), Some(CpuError::Overflow));
+    }
+
+    #[test]
+    fn rbase_overflow() {
+        // Push rbase to the top then one further.
+        let cpu = Cpu::new(Some(vec![109, Word::MAX, 109, 1, 99]));
+        assert_eq!(cpu.run().err(), Some(CpuError::RelBaseOverflow));
+
+        // Or offset past the top from a relative parameter.
+        let cpu = Cpu::new(Some(vec![109, Word::MAX, 204, 1, 99]));
+        assert_eq!(cpu.run().err(), Some(CpuError::RelBaseOverflow));
+
+        // A negative rbase is fine until it's used as an address.
+        let mut cpu = Cpu::new(Some(vec![109, -5, 204, 5, 204, 0, 99]));
+        cpu.verbose = false;
+        assert_eq!(cpu.resume(), Err(CpuError::NegativeAddress(-5)));
+        assert_eq!(cpu.outputs(), [109]);
+        assert_eq!(cpu.ip(), 4);
+    }
+
+    #[test]
+    fn large_program() {
+        // memory[0] = memory[4999] + memory[4999], past the old 4096 limit.
+        let mut prog = vec![0; 5000];
+        prog[..5].copy_from_slice(&[1, 4999, 4999, 0, 99]);
+        prog[4999] = 21;
+        let cpu = Cpu::new(Some(prog)).run().unwrap();
+        assert_eq!(cpu.memory.len(), 5000);
+        assert_eq!(cpu.memory[4999], 21);
+        assert_eq!(cpu.memory[0], 42);
+    }
+
+    #[test]
+    fn large_input_file() {
+        // Loading from a file must not truncate to a fixed size either.
+        let mut prog = vec![0; 5000];
+        prog[..5].copy_from_slice(&[1, 4999, 4999, 0, 99]);
+        prog[4999] = 21;
+        let src: Vec<String> = prog.iter().map(|v| v.to_string()).collect();
+        let path = std::env::temp_dir().join("aoc9_large_input_file.txt");
+        std::fs::write(&path, src.join(",")).unwrap();
+        let loaded = process_input(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let cpu = Cpu::new(Some(loaded)).run().unwrap();
+        assert!(cpu.memory.len() >= 5000);
+        assert_eq!(cpu.memory[4999], 21);
+        assert_eq!(cpu.memory[0], 42);
+    }
+
+    #[test]
+    fn memory_growth() {
+        let cpu = Cpu::new(Some(vec![1101, 1, 2, 10000, 99])).run().unwrap();
+        assert_eq!(cpu.memory.len(), 10001);
+        assert_eq!(cpu.memory[10000], 3);
+    }
+
+    #[test]
+    fn memory_limit() {
+        assert_eq!(
+            Cpu::new(Some(vec![1101, 1, 2, 1_000_000_000_000, 99]))
+                .run()
+                .err(),
+            Some(CpuError::MemoryLimitExceeded(1_000_000_000_000))
+        );
+
+        let cpu = CpuBuilder::new()
+            .program(vec![1101, 1, 2, 99, 1101, 1, 2, 100, 99])
+            .max_memory(100)
+            .build();
+        assert_eq!(cpu.run().err(), Some(CpuError::MemoryLimitExceeded(100)));
+    }
+
+    #[test]
+    fn queued_input() {
+        let mut cpu = Cpu::new(Some(vec![3, 0, 3, 1, 99]));
+        cpu.push_input(5);
+        cpu.push_input(-7);
+        let cpu = cpu.run().unwrap();
+        assert_eq!(cpu.memory[..2], [5, -7]);
+    }
+
+    #[test]
+    fn input_dest_not_dereferenced() {
+        // [5] holds 6, so reading through it would wrongly store at 6.
+        let mut cpu = Cpu::new(Some(vec![3, 5, 99, 0, 0, 6, 0]));
+        cpu.push_input(42);
+        let cpu = cpu.run().unwrap();
+        assert_eq!(cpu.memory[5], 42);
+        assert_eq!(cpu.memory[6], 0);
+    }
+
+    #[test]
+    fn day5_diagnostics() {
+        // Position mode equal-to-8 check, result stored at 9.
+        let mut cpu = Cpu::new(Some(vec![3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8]));
+        cpu.push_input(5);
+        assert_eq!(cpu.run().unwrap().memory[9], 0);
+
+        // Jump test storing whether the input was non-zero at 13.
+        let mut cpu = Cpu::new(Some(vec![
+            3, 12, 6, 12, 15, 1, 13, 14, 13, 4, 13, 99, -1, 0, 1, 9,
+        ]));
+        cpu.push_input(5);
+        assert_eq!(cpu.run().unwrap().memory[13], 1);
+    }
+
+    #[test]
+    fn buffered_output() {
+        let mut cpu = Cpu::new(Some(vec![104, 50, 99]));
+        cpu.verbose = false;
+        let cpu = cpu.run().unwrap();
+        assert_eq!(cpu.outputs(), [50]);
+    }
+
+    #[test]
+    fn output_hook() {
+        let seen = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut cpu = Cpu::new(Some(vec![104, 1, 104, 2, 104, 3, 99]));
+        cpu.verbose = false;
+        let sink = seen.clone();
+        cpu.on_output(move |v| sink.borrow_mut().push(v));
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert_eq!(*seen.borrow(), [1]);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(*seen.borrow(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn pending_io() {
+        let mut cpu = Cpu::new(Some(vec![3, 9, 4, 9, 104, 5, 99]));
+        cpu.verbose = false;
+        cpu.push_input(1);
+        cpu.push_input(2);
+        assert_eq!(cpu.pending_input(), 2);
+        assert_eq!(cpu.pending_output(), 0);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.pending_input(), 1);
+        assert_eq!(cpu.pending_output(), 2);
+        cpu.drain_outputs();
+        assert_eq!(cpu.pending_output(), 0);
+    }
+
+    #[test]
+    fn output_tee() {
+        let seen = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut cpu = Cpu::new(Some(vec![104, 7, 99]));
+        cpu.verbose = false;
+        let (tx, rx) = std::sync::mpsc::channel();
+        cpu.connect_output(tx);
+        for tag in &["a", "b"] {
+            let sink = seen.clone();
+            cpu.on_output(move |v| sink.borrow_mut().push((*tag, v)));
+        }
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert_eq!(cpu.outputs(), [7]);
+        assert_eq!(rx.try_recv(), Ok(7));
+        assert_eq!(*seen.borrow(), [("a", 7), ("b", 7)]);
+    }
+
+    #[test]
+    fn drain_outputs() {
+        let mut cpu = Cpu::new(Some(vec![104, 1, 104, 2, 104, 3, 99]));
+        cpu.verbose = false;
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        let drained: Vec<Word> = cpu.drain_outputs().collect();
+        assert_eq!(drained, [1, 2, 3]);
+        assert!(cpu.outputs().is_empty());
+        assert_eq!(cpu.drain_outputs().count(), 0);
+    }
+
+    #[test]
+    fn feedback_loop() {
+        let program = vec![
+            3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1,
+            28, 1005, 28, 6, 99, 0, 0, 5,
+        ];
+        let mut amps: Vec<Cpu> = [9, 8, 7, 6, 5]
+            .iter()
+            .map(|&phase| {
+                let mut cpu = Cpu::new(Some(program.clone()));
+                cpu.verbose = false;
+                cpu.push_input(phase);
+                cpu
+            })
+            .collect();
+
+        let mut signal = 0;
+        'feedback: loop {
+            for amp in amps.iter_mut() {
+                amp.push_input(signal);
+                match amp.run_until_output().unwrap() {
+                    Some(v) => signal = v,
+                    None => break 'feedback,
+                }
+            }
+        }
+        assert_eq!(signal, 139629729);
+        assert_eq!(amps[4].run_until_output(), Ok(None));
+    }
+
+    #[test]
+    fn collect_outputs() {
+        assert_eq!(run_collect(vec![3, 0, 4, 0, 99], &[7]), vec![7]);
+        assert_eq!(run_collect(vec![3, 0, 4, 0, 3, 0, 99], &[1]), vec![1]);
+    }
+
+    #[test]
+    fn amplifiers() {
+        let program = [
+            3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0,
+        ];
+        assert_eq!(amplify(&program, &[4, 3, 2, 1, 0], 0), 43210);
+
+        let program = [
+            3, 23, 3, 24, 1002, 24, 10, 24, 1002, 23, -1, 23, 101, 5, 23, 23, 1, 24, 23, 23, 4, 23,
+            99, 0, 0,
+        ];
+        assert_eq!(amplify(&program, &[0, 1, 2, 3, 4], 0), 54321);
+
+        let program = [
+            3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1,
+            28, 1005, 28, 6, 99, 0, 0, 5,
+        ];
+        assert_eq!(amplify_loop(&program, &[9, 8, 7, 6, 5], 0), 139629729);
+    }
+
+    #[test]
+    fn registers() {
+        let mut cpu = Cpu::new(Some(vec![109, 3, 99]));
+        assert_eq!(cpu.ip(), 0);
+        assert_eq!(cpu.rbase(), 0);
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert_eq!(cpu.ip(), 2);
+        assert_eq!(cpu.rbase(), 3);
+    }
+
+    #[test]
+    fn next_opcode() {
+        let mut cpu = Cpu::new(Some(vec![104, 1, 203, 0, 99]));
+        cpu.verbose = false;
+        assert_eq!(cpu.peek_next_opcode(), Some(4));
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert_eq!(cpu.peek_next_opcode(), Some(3));
+        assert_eq!(cpu.ip(), 2);
+        cpu.ip = 5;
+        assert_eq!(cpu.peek_next_opcode(), None);
+    }
+
+    #[test]
+    fn output_batches() {
+        let mut cpu = Cpu::new(Some(vec![104, 1, 104, 2, 104, 3, 104, 4, 99]));
+        cpu.verbose = false;
+        assert_eq!(cpu.run_until_outputs(3), Ok(Some(vec![1, 2, 3])));
+        assert_eq!(cpu.ip(), 6);
+        assert_eq!(cpu.run_until_outputs(3), Ok(Some(vec![4])));
+        assert_eq!(cpu.run_until_outputs(3), Ok(None));
+    }
+
+    #[test]
+    fn default_input() {
+        // Output the first input, then the second plus ten.
+        let prog = vec![3, 13, 4, 13, 3, 13, 101, 10, 13, 13, 4, 13, 99, 0];
+        let mut cpu = CpuBuilder::new()
+            .program(prog)
+            .verbose(false)
+            .default_input(1)
+            .input(5)
+            .build();
+        assert!(cpu.interactive);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.outputs(), [5, 11]);
+    }
+
+    #[test]
+    fn run_until_input() {
+        // Output 1, read [13], output it doubled, then halt.
+        let prog = vec![104, 1, 3, 13, 1002, 13, 2, 13, 4, 13, 99, 0, 0, 0];
+        let mut cpu = Cpu::new(Some(prog));
+        cpu.verbose = false;
+        assert_eq!(cpu.run_until_input(), Ok(RunState::NeedInput));
+        assert_eq!(cpu.ip(), 2);
+        assert_eq!(cpu.outputs(), [1]);
+        assert_eq!(cpu.run_until_input(), Ok(RunState::NeedInput));
+        assert_eq!(cpu.ip(), 2);
+
+        cpu.push_input(21);
+        assert_eq!(cpu.run_until_input(), Ok(RunState::Halted));
+        assert_eq!(cpu.outputs(), [1, 42]);
+    }
+
+    #[test]
+    fn undo() {
+        let mut cpu = Cpu::new(Some(vec![1101, 2, 3, 0, 3, 1, 4, 1, 99]));
+        cpu.verbose = false;
+        cpu.undo_depth = 2;
+        assert!(!cpu.undo());
+
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert_eq!((cpu.ip(), cpu.peek(0)), (4, 5));
+        assert!(cpu.undo());
+        assert_eq!((cpu.ip(), cpu.peek(0)), (0, 1101));
+        assert!(!cpu.undo());
+
+        // Replay through the input and output, then back over both.
+        cpu.push_input(9);
+        assert_eq!(cpu.run_n(3), Ok(RunState::Continue));
+        assert_eq!((cpu.peek(1), cpu.outputs()), (9, &[9][..]));
+        assert!(cpu.undo());
+        assert!(cpu.outputs().is_empty());
+        assert!(cpu.undo());
+        assert_eq!((cpu.ip(), cpu.peek(1), cpu.pending_input()), (4, 2, 1));
+        // Only two steps are kept.
+        assert!(!cpu.undo());
+        assert_eq!(cpu.peek(0), 5);
+
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.outputs(), [9]);
+    }
+
+    #[test]
+    fn custom_opcode() {
+        // 10 does nothing; 11 adds its two parameters and outputs the sum.
+        // The second 11 adds 1 to relative [1], which holds 1111.
+        let prog = vec![10, 1111, 20, 22, 10, 2111, 1, 1, 99];
+        let mut cpu = CpuBuilder::new().program(prog).quiet().build();
+        assert_eq!(cpu.resume(), Err(CpuError::InvalidOpcode(10, 0)));
+
+        cpu.register_op(10, 0, Box::new(|_, _| {}));
+        cpu.register_op(
+            11,
+            2,
+            Box::new(|cpu, args| {
+                let sum = cpu.value_of(args[0]).unwrap() + cpu.value_of(args[1]).unwrap();
+                cpu.outputs.push(sum);
+            }),
+        );
+        assert_eq!(
+            cpu.step_detailed(),
+            Ok((
+                Some(Instruction::CUSTOM(10, 0, [Parameter::Immediate(0); 3])),
+                RunState::Continue
+            ))
+        );
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.outputs(), [42, 1112]);
+        assert_eq!(cpu.op_counts()["HALT"], 1);
+    }
+
+    #[test]
+    fn halt_on_output() {
+        let mut cpu = Cpu::new(Some(vec![104, 7, 104, 8, 99]));
+        cpu.verbose = false;
+        cpu.halt_on_output = true;
+        assert_eq!(cpu.resume(), Ok(RunState::OutputReady(7)));
+        assert_eq!((cpu.ip(), cpu.outputs()), (2, &[7][..]));
+        assert_eq!(cpu.run_until_outputs(2), Ok(Some(vec![8])));
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+
+        cpu.reset();
+        assert_eq!(cpu.run_until_output(), Ok(Some(7)));
+        assert_eq!(cpu.run_until_output(), Ok(Some(8)));
+        assert_eq!(cpu.run_until_output(), Ok(None));
+    }
+
+    #[test]
+    fn single_step() {
+        let mut cpu = Cpu::new(Some(vec![104, 1, 1101, 1, 2, 0, 99]));
+        cpu.verbose = false;
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert_eq!(cpu.ip, 2);
+        assert_eq!(cpu.outputs(), [1]);
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert_eq!(cpu.ip, 6);
+        assert_eq!(cpu.memory[0], 3);
+        assert_eq!(cpu.step(), Ok(RunState::Halted));
+        assert_eq!(cpu.ip, 6);
+    }
+
+    #[test]
+    fn step_detailed() {
+        use Parameter::*;
+        let mut cpu = Cpu::new(Some(vec![104, 1, 1101, 1, 2, 0, 3, 0, 99]));
+        cpu.interactive = false;
+        cpu.verbose = false;
+        assert_eq!(
+            cpu.step_detailed(),
+            Ok((
+                Some(Instruction::OUTPUT([Immediate(1)])),
+                RunState::Continue
+            ))
+        );
+        assert_eq!(
+            cpu.step_detailed(),
+            Ok((
+                Some(Instruction::ADD([Immediate(1), Immediate(2), Position(0)])),
+                RunState::Continue
+            ))
+        );
+        assert_eq!(cpu.step_detailed(), Ok((None, RunState::NeedInput)));
+        cpu.push_input(5);
+        assert_eq!(
+            cpu.step_detailed(),
+            Ok((Some(Instruction::INPUT([Position(0)])), RunState::Continue))
+        );
+        assert_eq!(
+            cpu.step_detailed(),
+            Ok((Some(Instruction::HALT), RunState::Halted))
+        );
+    }
+
+    #[test]
+    fn bounded_run() {
+        let mut cpu = Cpu::new(Some(vec![1101, 2, 3, 0, 99]));
+        assert_eq!(cpu.run_n(1), Ok(RunState::Continue));
+        assert_eq!(cpu.memory[0], 5);
+        assert_eq!(cpu.ip, 4);
+        assert_eq!(cpu.run_n(5), Ok(RunState::Halted));
+        assert_eq!(cpu.run_n(0), Ok(RunState::Continue));
+    }
+
+    #[test]
+    fn need_input() {
+        let mut cpu = Cpu::new(Some(vec![3, 0, 3, 1, 1, 0, 1, 2, 99]));
+        cpu.interactive = false;
+        cpu.push_input(3);
+        assert_eq!(cpu.resume(), Ok(RunState::NeedInput));
+        assert_eq!(cpu.ip, 2);
+        assert_eq!(cpu.resume(), Ok(RunState::NeedInput));
+        cpu.push_input(4);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.memory[..3], [3, 4, 7]);
+    }
+
+    #[test]
+    fn read_print_loop() {
+        // Forever: print a prompt, read a value and echo it.
+        let prog = vec![104, 62, 3, 100, 4, 100, 1105, 1, 0];
+        let mut cpu = CpuBuilder::new().program(prog.clone()).quiet().build();
+        assert_eq!(cpu.resume(), Ok(RunState::NeedInput));
+        assert_eq!((cpu.ip(), cpu.outputs()), (2, &[62][..]));
+        cpu.push_input(97);
+        assert_eq!(cpu.resume(), Ok(RunState::NeedInput));
+        assert_eq!(cpu.outputs(), [62, 97, 62]);
+        assert_eq!(cpu.run_until_input(), Ok(RunState::NeedInput));
+        assert_eq!(cpu.outputs(), [62, 97, 62]);
+
+        // Non-blocking cpus yield the same way when told to stop on EOF,
+        // rather than spinning on the default -1.
+        let mut cpu = CpuBuilder::new()
+            .program(prog)
+            .quiet()
+            .on_eof(EofAction::Halt)
+            .build();
+        cpu.input_mode = InputMode::NonBlocking;
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!((cpu.ip(), cpu.halt_ip()), (2, None));
+        cpu.push_input(98);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.outputs(), [62, 98, 62]);
+    }
+
+    #[test]
+    fn non_blocking_input() {
+        let mut cpu = Cpu::new(Some(vec![3, 10, 3, 11, 3, 12, 99]));
+        cpu.input_mode = InputMode::NonBlocking;
+        assert!(!cpu.is_idle());
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert!(cpu.is_idle());
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert!(cpu.is_idle());
+        cpu.push_input(5);
+        assert_eq!(cpu.step(), Ok(RunState::Continue));
+        assert!(!cpu.is_idle());
+        assert_eq!(cpu.memory[10..], [-1, -1, 5]);
+    }
+
+    #[test]
+    fn eof_action() {
+        let prog = vec![3, 10, 4, 10, 99];
+        let eof = |action| {
+            let mut cpu = CpuBuilder::new()
+                .program(prog.clone())
+                .verbose(false)
+                .on_eof(action)
+                .build();
+            cpu.input_mode = InputMode::NonBlocking;
+            cpu
+        };
+
+        let mut cpu = eof(EofAction::Halt);
+        cpu.max_cycles = Some(1);
+        cpu.undo_depth = 4;
+        for _ in 0..3 {
+            assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        }
+        // Retries cost nothing, as the INPUT never ran.
+        assert_eq!((cpu.ip, cpu.cycles()), (0, 0));
+        assert_eq!(cpu.op_counts()["INPUT"], 0);
+        assert!(!cpu.undo());
+        cpu.max_cycles = None;
+        cpu.push_input(3);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.outputs(), [3]);
+
+        let mut cpu = eof(EofAction::Value(9));
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.outputs(), [9]);
+
+        let mut cpu = eof(EofAction::Error);
+        assert_eq!(cpu.resume(), Err(CpuError::UnexpectedEof));
+
+        // A queued value survives an INPUT that faults, here on writing to
+        // an immediate.
+        let mut cpu = CpuBuilder::new().program(vec![103, 5, 99]).quiet().build();
+        cpu.push_input(7);
+        assert_eq!(cpu.resume(), Err(CpuError::InvalidMode(1)));
+        assert_eq!(cpu.pending_input(), 1);
+    }
+
+    #[test]
+    fn parse_comments() {
+        let src = "// double 21 and output it\n\
+                   \n\
+                   1002, 7, 2, 7,  // [7] *= 2\n\
+                   4, 7\n\
+                   99, 21 // halt, then the data\n";
+        assert_eq!(parse_program(src), Ok(vec![1002, 7, 2, 7, 4, 7, 99, 21]));
+        assert_eq!(run_collect(parse_program(src).unwrap(), &[]), vec![42]);
+    }
+
+    #[test]
+    fn input_file() {
+        let path = std::env::temp_dir().join("aoc9_input_file.txt");
+        std::fs::write(&path, "104,-3,99\n").unwrap();
+        assert_eq!(process_input(&path).unwrap(), vec![104, -3, 99]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(process_input(&path).is_err());
+    }
+
+    #[test]
+    fn run_from_file() {
+        let path = std::env::temp_dir().join("aoc9_run_from_file.txt");
+        std::fs::write(&path, "3,9,1002,9,3,9,4,9,99,0\n").unwrap();
+        assert_eq!(run_file(&path, &[14]), Ok(vec![42]));
+        std::fs::write(&path, "1,2,x").unwrap();
+        assert!(matches!(run_file(&path, &[]), Err(CpuError::Load(_))));
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(run_file(&path, &[]), Err(CpuError::Load(_))));
+    }
+
+    #[test]
+    fn parse_whitespace() {
+        for src in &["1,2,3\n", "1, 2, 3", "1,2,3,"] {
+            assert_eq!(parse_program(src), Ok(vec![1, 2, 3]));
+        }
+        assert!(parse_program("1,x,3").is_err());
+        assert_eq!(parse_program("104,42,99"), Ok(vec![104, 42, 99]));
+
+        let path = std::env::temp_dir().join("aoc9_parse_whitespace.txt");
+        std::fs::write(&path, "1,2x,3").unwrap();
+        let err = process_input(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("\"2x\""));
+    }
+
+    #[test]
+    fn program_string() {
+        let src = "1002,4,3,4,33,-7";
+        let prog = parse_program(src).unwrap();
+        let cpu = Cpu::new(Some(prog.clone()));
+        assert_eq!(cpu.to_program_string(), src);
+        assert_eq!(parse_program(&cpu.to_program_string()), Ok(prog));
+
+        // Zero cells grown past the program are dropped, written ones kept.
+        let mut cpu = CpuBuilder::new()
+            .program(vec![1101, 0, 0, 9, 1101, 2, 3, 7, 99])
+            .memory_size(16)
+            .quiet()
+            .build();
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.to_program_string(), "1101,0,0,9,1101,2,3,5,99");
+        cpu.poke(11, -1);
+        assert_eq!(cpu.to_program_string(), "1101,0,0,9,1101,2,3,5,99,0,0,-1");
+    }
+
+    #[test]
+    fn ascii_io() {
+        let mut cpu = Cpu::new(Some(vec![104, 72, 104, 105, 104, 10, 104, 1000, 99]));
+        cpu.verbose = false;
+        cpu.push_ascii_line("A\n");
+        assert_eq!(cpu.inputs, [65, 10]);
+        assert_eq!(cpu.run_ascii(), Ok(("Hi\n".to_string(), vec![1000])));
+    }
+
+    #[test]
+    fn mode_digits() {
+        assert_eq!(decode_modes(1002, 3), [0, 1, 0]);
+        assert_eq!(decode_modes(21101, 3), [1, 1, 2]);
+        assert_eq!(decode_modes(204, 1), [2]);
+        assert!(decode_modes(99, 0).is_empty());
+    }
+
+    #[test]
+    fn ascii_encoding() {
+        let mut cpu = Cpu::new(Some(vec![99]));
+        cpu.push_ascii("WALK").unwrap();
+        assert_eq!(cpu.inputs, [87, 65, 76, 75, 10]);
+
+        let mut cpu = Cpu::new(Some(vec![99]));
+        cpu.push_ascii_lines(&["NOT A J", "RUN\n"]).unwrap();
+        assert_eq!(cpu.inputs.len(), 12);
+        assert_eq!(cpu.inputs.back(), Some(&10));
+        assert_eq!(cpu.inputs[7], 10);
+
+        let mut cpu = Cpu::new(Some(vec![99]));
+        assert_eq!(cpu.push_ascii("café"), Err(CpuError::NonAscii('é')));
+        assert_eq!(
+            cpu.push_ascii_lines(&["ok", "naïve"]),
+            Err(CpuError::NonAscii('ï'))
+        );
+        assert!(cpu.inputs.is_empty());
+    }
+
+    #[test]
+    fn ascii_echo() {
+        let mut cpu = Cpu::new(Some(vec![99]));
+        assert_eq!(cpu.echo(72), "> 72\n");
+        cpu.ascii_output = true;
+        let text: String = [72, 73, 10].iter().map(|&v| cpu.echo(v)).collect();
+        assert_eq!(text, "HI\n");
+        assert_eq!(cpu.echo(1000), "1000\n");
+    }
+
+    #[test]
+    fn disassembly() {
+        assert_eq!(
+            disassemble(&[1101, 100, -1, 4, 0, 99]),
+            ["ADD I(100) I(-1) -> P(4)"]
+        );
+        assert_eq!(
+            disassemble(&[3, 9, 1005, 9, 7, 204, -1, 99]),
+            ["INPUT -> P(9)", "JNZ P(9) I(7)", "OUTPUT R(-1)", "HALT"]
+        );
+    }
+
+    #[test]
+    fn assembler() {
+        let run = |prog| {
+            let mut cpu = Cpu::new(Some(prog));
+            cpu.verbose = false;
+            cpu.run().unwrap().outputs().to_vec()
+        };
+
+        // Count down from 3 in cell 10.
+        let prog = assemble(
+            "loop: out 10            // print the counter
+                   add 10 #-1 -> 10
+                   jnz 10 @loop
+                   halt
+                   data 3",
+        );
+        assert_eq!(prog, [4, 10, 1001, 10, -1, 10, 1005, 10, 0, 99, 3]);
+        assert_eq!(run(prog), [3, 2, 1]);
+
+        // Relative parameters, and a label used before it's defined.
+        let prog = assemble(
+            "RB #20
+             MUL #6 #7 -> r0
+             jz #0 @end
+             out #-1
+             end: out r0
+             halt",
+        );
+        assert_eq!(prog[..6], [109, 20, 21102, 6, 7, 0]);
+        assert_eq!(assemble("jnz r1 @20"), [1205, 1, 20]);
+        assert_eq!(disassemble(&prog)[1], "MUL I(6) I(7) -> R(0)");
+        assert_eq!(run(prog), [42]);
+    }
+
+    #[test]
+    #[should_panic(expected = "line 2: unknown label \"nowhere\"")]
+    fn assembler_bad_label() {
+        assemble("halt\njz #0 @nowhere");
+    }
+
+    #[test]
+    fn disassembly_json() {
+        assert_eq!(
+            disassemble_json(&[104, 5, 99]),
+            r#"[{"ip":0,"opcode":4,"name":"OUTPUT","params":[{"mode":1,"value":5}],"width":2},"#
+                .to_string()
+                + r#"{"ip":2,"opcode":99,"name":"HALT","params":[],"width":1}]"#
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&disassemble_json(&[21101, 1, -2, 3])).unwrap();
+        assert_eq!(json[0]["params"][2]["mode"], 2);
+        assert_eq!(json[0]["params"][1]["value"], -2);
+        assert_eq!(disassemble_json(&[]), "[]");
+    }
+
+    #[test]
+    fn instruction_visitor() {
+        let prog = [3, 12, 6, 12, 15, 1, 13, 14, 13, 4, 13, 99, -1, 0, 1, 9];
+        let mut jumps = 0;
+        let mut seen = Vec::new();
+        for_each_instruction(&prog, |ip, opcode, modes| {
+            if opcode == 5 || opcode == 6 {
+                jumps += 1;
+            }
+            seen.push((ip, opcode, modes.to_vec()));
+        });
+        assert_eq!(jumps, 1);
+        assert_eq!(seen.len(), 5);
+        assert_eq!(seen[1], (2, 6, vec![0, 0]));
+        assert_eq!(seen[4], (11, 99, vec![]));
+    }
+
+    #[test]
+    fn breakpoint() {
+        let mut cpu = Cpu::new(Some(vec![1101, 1, 2, 0, 1001, 0, 10, 0, 99]));
+        cpu.add_breakpoint(4);
+        cpu.add_breakpoint(8);
+        assert_eq!(cpu.resume(), Ok(RunState::Breakpoint(4)));
+        assert_eq!(cpu.ip, 4);
+        assert_eq!(cpu.memory[0], 3);
+        cpu.clear_breakpoint(8);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.memory[0], 13);
+
+        // A breakpoint on an INPUT doesn't fire again once input arrives.
+        let mut cpu = CpuBuilder::new().program(vec![3, 0, 99]).quiet().build();
+        cpu.add_breakpoint(0);
+        assert_eq!(cpu.resume(), Ok(RunState::Breakpoint(0)));
+        assert_eq!(cpu.resume(), Ok(RunState::NeedInput));
+        cpu.push_input(5);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.memory[0], 5);
+    }
+
+    #[test]
+    fn watchpoint() {
+        let mut cpu = Cpu::new(Some(vec![1101, 1, 2, 0, 1101, 3, 4, 5, 99]));
+        cpu.watch(0);
+        assert_eq!(
+            cpu.resume(),
+            Ok(RunState::Watchpoint {
+                addr: 0,
+                old: 1101,
+                new: 3
+            })
+        );
+        assert_eq!(cpu.ip, 4);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.memory[5], 7);
+    }
+
+    #[test]
+    fn monitor_commands() {
+        assert_eq!(parse_command("s\n"), Some(MonitorCmd::Step));
+        assert_eq!(parse_command(" c "), Some(MonitorCmd::Continue));
+        assert_eq!(parse_command("m 12"), Some(MonitorCmd::Memory(12)));
+        assert_eq!(parse_command("r"), Some(MonitorCmd::Registers));
+        assert_eq!(parse_command("b 4"), Some(MonitorCmd::Break(4)));
+        assert_eq!(parse_command("q"), Some(MonitorCmd::Quit));
+        assert_eq!(parse_command(""), None);
+        assert_eq!(parse_command("m"), None);
+        assert_eq!(parse_command("b -1"), None);
+        assert_eq!(parse_command("s 1"), None);
+        assert_eq!(parse_command("x"), None);
+    }
+
+    #[test]
+    fn builder() {
+        let cpu = CpuBuilder::new()
+            .program(vec![3, 0, 3, 1, 1, 0, 1, 2, 4, 2, 99])
+            .inputs(&[20])
+            .input(22)
+            .verbose(false)
+            .memory_size(64)
+            .build();
+        assert_eq!(cpu.memory.len(), 64);
+        let cpu = cpu.run().unwrap();
+        assert_eq!(cpu.outputs(), [42]);
+
+        let cpu = CpuBuilder::new()
+            .program(vec![99; 10])
+            .memory_size(4)
+            .build();
+        assert_eq!(cpu.memory.len(), 10);
+    }
+
+    #[test]
+    fn presized_memory() {
+        // Store 1 at 10_000, past the requested size, then halt.
+        let mut prog = vec![1101, 0, 1, 10_000, 99];
+        prog.resize(100, 0);
+        let cpu = CpuBuilder::new()
+            .program(prog)
+            .quiet()
+            .memory_size(8192)
+            .build();
+        assert_eq!(cpu.memory.len(), 8192);
+        assert_eq!(cpu.memory[..5], [1101, 0, 1, 10_000, 99]);
+        let cpu = cpu.run().unwrap();
+        assert_eq!(cpu.memory.len(), 10_001);
+        assert_eq!(cpu.memory[10_000], 1);
+    }
+
+    #[test]
+    fn sparse_memory() {
+        // Store 3 + 4 a million cells up and output it back.
+        let cpu = CpuBuilder::new()
+            .program(vec![109, 1_000_000, 21101, 3, 4, 0, 204, 0, 99])
+            .verbose(false)
+            .sparse_memory(true)
+            .build();
+        let cpu = cpu.run().unwrap();
+        assert_eq!(cpu.outputs(), [7]);
+        assert_eq!(cpu.memory.len(), 9);
+        assert_eq!(cpu.sparse.as_ref().unwrap().read(1_000_000), 7);
+    }
+
+    // Write-only device that records everything written to it.
+    struct Console(std::rc::Rc<RefCell<Vec<Word>>>);
+
+    impl Memory for Console {
+        fn read(&self, _: usize) -> Word {
+            0
+        }
+
+        fn write(&mut self, _: usize, val: Word) {
+            self.0.borrow_mut().push(val);
+        }
+    }
+
+    // Read-only device where each cell holds twice its address.
+    struct Doubles;
+
+    impl Memory for Doubles {
+        fn read(&self, addr: usize) -> Word {
+            addr as Word * 2
+        }
+
+        fn write(&mut self, _: usize, _: Word) {}
+    }
+
+    #[test]
+    fn mapped_memory() {
+        // Write 7 then 11 to [100], then output [200].
+        let prog = vec![1101, 3, 4, 100, 1101, 5, 6, 100, 4, 200, 99];
+        let mut cpu = CpuBuilder::new().program(prog).quiet().build();
+        let written = std::rc::Rc::new(RefCell::new(Vec::new()));
+        cpu.map(100..101, Console(written.clone()));
+        cpu.map(200..300, Doubles);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(*written.borrow(), [7, 11]);
+        assert_eq!(cpu.outputs(), [400]);
+        assert_eq!(cpu.memory.len(), 11);
+    }
+
+    #[test]
+    fn channels() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut a = Cpu::new(Some(vec![104, 42, 99]));
+        a.verbose = false;
+        a.connect_output(tx);
+        let mut b = Cpu::new(Some(vec![3, 0, 3, 1, 4, 0, 4, 1, 99]));
+        b.verbose = false;
+        b.set_input_receiver(rx);
+
+        a.run().unwrap();
+        let b = b.run().unwrap();
+        assert_eq!(b.outputs(), [42, -1]);
+    }
+
+    #[test]
+    fn op_counts() {
+        let cpu = Cpu::new(Some(countdown(5))).run().unwrap();
+        let counts = cpu.op_counts();
+        assert_eq!(counts["ADD"], 5);
+        assert_eq!(counts["JNZ"], 5);
+        assert_eq!(counts["HALT"], 1);
+        assert_eq!(counts["MUL"], 0);
+    }
+
+    #[test]
+    fn timing() {
+        let cpu = Cpu::new(Some(countdown(1000))).run().unwrap();
+        assert!(cpu.timing().values().all(|t| t.is_zero()));
+
+        let mut cpu = Cpu::new(Some(countdown(1000)));
+        cpu.profile = true;
+        let cpu = cpu.run().unwrap();
+        let times = cpu.timing();
+        assert!(times["ADD"] > Duration::ZERO);
+        assert!(times["JNZ"] > Duration::ZERO);
+        assert_eq!(times["MUL"], Duration::ZERO);
+    }
+
+    #[test]
+    fn access_stats() {
+        let cpu = Cpu::new(Some(countdown(5))).run().unwrap();
+        assert!(cpu.access_stats().is_empty());
+
+        // [20] is read twice and written once a lap.
+        let mut cpu = Cpu::new(Some(countdown(5)));
+        cpu.profile = true;
+        let cpu = cpu.run().unwrap();
+        let stats = cpu.access_stats();
+        assert_eq!(stats[&20], (10, 5));
+        assert_eq!(stats.len(), 1);
+    }
+
+    #[test]
+    fn cycles() {
+        let cpu = Cpu::new(Some(vec![1101, 100, -1, 4, 0, 99])).run().unwrap();
+        assert_eq!(cpu.cycles(), 2);
+
+        let mut cpu = Cpu::new(Some(vec![3, 0, 99]));
+        cpu.interactive = false;
+        assert_eq!(cpu.resume(), Ok(RunState::NeedInput));
+        assert_eq!(cpu.cycles(), 0);
+    }
+
+    #[test]
+    fn reset() {
+        let mut cpu = Cpu::new(Some(vec![3, 0, 109, 3, 1002, 0, 2, 0, 204, -3, 99]));
+        cpu.verbose = false;
+        cpu.push_input(21);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.outputs(), [42]);
+        assert_eq!(cpu.memory[0], 42);
+
+        cpu.reset();
+        assert_eq!(cpu.memory[..2], [3, 0]);
+        assert!(cpu.outputs().is_empty());
+        cpu.push_input(21);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.outputs(), [42]);
+        assert_eq!(cpu.memory[0], 42);
+    }
+
+    #[test]
+    fn memory_changes() {
+        let cpu = Cpu::new(Some(vec![1, 0, 0, 0, 99])).run().unwrap();
+        assert_eq!(cpu.diff(), [(0, 1, 2)]);
+
+        assert_eq!(
+            memory_diff(&[1, 2, 3], &[1, 5, 3, 0, 7]),
+            [(1, 2, 5), (4, 0, 7)]
+        );
+        assert_eq!(memory_diff(&[1, 2], &[1]), [(1, 2, 0)]);
+
+        let cpu = CpuBuilder::new()
+            .program(vec![109, 50, 21101, 3, 4, 0, 1101, 0, 0, 1, 99])
+            .quiet()
+            .sparse_memory(true)
+            .build()
+            .run()
+            .unwrap();
+        assert_eq!(cpu.diff(), [(1, 50, 0), (50, 0, 7)]);
+    }
+
+    #[test]
+    fn diagnostics() {
+        assert_eq!(diagnostic_code(&[0, 0, 0, 7_259_358]), Ok(7_259_358));
+        assert_eq!(diagnostic_code(&[42]), Ok(42));
+        assert_eq!(diagnostic_code(&[0, 3, 0, 15]), Err(vec![0, 3, 0, 15]));
+        assert_eq!(diagnostic_code(&[]), Err(vec![]));
+
+        // A run that passes two tests, then reports 99.
+        let outputs = run_collect(vec![104, 0, 104, 0, 104, 99, 99], &[1]);
+        assert_eq!(diagnostic_code(&outputs), Ok(99));
+    }
+
+    #[test]
+    fn noun_verb_search() {
+        // Day 2 style: find the noun and verb whose cells sum to 70.
+        let mut cpu = Cpu::new(Some(vec![1, 0, 0, 0, 99, 10, 20, 30, 40]));
+        let mut found = None;
+        'search: for noun in 5..9 {
+            for verb in 5..9 {
+                cpu.reset();
+                cpu.poke(1, noun);
+                cpu.poke(2, verb);
+                assert_eq!(cpu.resume(), Ok(RunState::Halted));
+                if cpu.peek(0) == 70 {
+                    found = Some((noun, verb));
+                    break 'search;
+                }
+            }
+        }
+        assert_eq!(found, Some((7, 8)));
+        assert_eq!(cpu.peek(100), 0);
+        cpu.poke(100, 1);
+        assert_eq!(cpu.memory.len(), 101);
+    }
+
+    #[test]
+    fn load_segment() {
+        let mut cpu = Cpu::new(Some(vec![1, 10, 12, 0, 99]));
+        cpu.load_at(10, &[7, 7, 7]);
+        assert_eq!(cpu.memory.len(), 13);
+        assert_eq!(cpu.memory[10..], [7, 7, 7]);
+        assert_eq!(cpu.run().unwrap().memory[0], 14);
+    }
+
+    #[test]
+    fn memory_dump() {
+        let cpu = Cpu::new(Some(vec![1101, 100, -1, 4, 0]));
+        assert_eq!(cpu.dump(0, 5, 5), "0000: 1101 100 -1 4 0\n");
+        assert_eq!(cpu.dump(3, 4, 3), "0003: 4 0 0\n0006: 0\n");
+        assert_eq!(cpu.dump(0, 0, 5), "");
+    }
+
+    #[test]
+    fn noun_verb() {
+        let program = [1101, 0, 0, 0, 99];
+        assert_eq!(find_noun_verb(&program, 150), Some((51, 99)));
+        assert_eq!(find_noun_verb(&program, 7), Some((0, 7)));
+        assert_eq!(find_noun_verb(&program, 500), None);
+    }
+
+    #[test]
+    fn clone_cpu() {
+        let mut cpu = Cpu::new(Some(vec![3, 0, 1002, 0, 2, 0, 4, 0, 99]));
+        cpu.verbose = false;
+        cpu.push_input(4);
+        let original = cpu.clone();
+        assert_eq!(cpu, original);
+
+        let mut copy = cpu.clone();
+        assert_eq!(copy.resume(), Ok(RunState::Halted));
+        assert_eq!(copy.outputs(), [8]);
+        assert_ne!(copy, cpu);
+        assert_eq!(cpu, original);
+        assert_eq!(cpu.ip, 0);
+        assert!(cpu.outputs().is_empty());
+        assert!(format!("{:?}", copy).starts_with("Cpu { ip: 8,"));
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut cpu = Cpu::new(Some(countdown(5)));
+        cpu.verbose = false;
+        cpu.push_input(7);
+        for _ in 0..7 {
+            assert_eq!(cpu.step(), Ok(RunState::Continue));
+        }
+
+        let mut restored = Cpu::restore(&cpu.snapshot()).unwrap();
+        assert_eq!(restored.ip, cpu.ip);
+        assert_eq!(restored.inputs, [7]);
+        assert_eq!(restored.cycles(), 7);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(restored.resume(), Ok(RunState::Halted));
+        assert_eq!(restored.cycles(), 11);
+        assert_eq!(restored, cpu);
+        assert!(Cpu::restore("{}").is_err());
+    }
+
+    #[test]
+    fn cycle_limit() {
+        let mut cpu = CpuBuilder::new()
+            .program(vec![1105, 1, 0])
+            .max_cycles(1000)
+            .build();
+        assert_eq!(cpu.resume(), Ok(RunState::CycleLimitExceeded(0)));
+        assert_eq!(cpu.cycles(), 1000);
+        assert_eq!(cpu.step(), Ok(RunState::CycleLimitExceeded(0)));
+    }
+
+    #[test]
+    fn trace_sink() {
+        let mut cpu = Cpu::new(Some(vec![109, 4, 21101, 1, 2, 0, 204, 0, 99]));
+        cpu.verbose = false;
+        cpu.trace_sink = Some(Vec::new());
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        let trace = cpu.trace_sink.unwrap();
+        let ops: Vec<_> = trace.iter().map(|t| t.op).collect();
+        assert_eq!(ops, ["RELBASE", "ADD", "OUTPUT", "HALT"]);
+        assert_eq!(
+            trace[2],
+            Trace {
+                cycle: 3,
+                ip: 6,
+                op: "OUTPUT",
+                params: vec![Parameter::Position(4)],
+                rbase: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn trace_filter() {
+        let mut cpu = CpuBuilder::new()
+            .program(vec![109, 4, 104, 1, 21101, 1, 2, 0, 204, 0, 99])
+            .quiet()
+            .trace_filter(&[4])
+            .build();
+        cpu.trace_sink = Some(Vec::new());
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        let trace = cpu.trace_sink.unwrap();
+        let seen: Vec<_> = trace.iter().map(|t| (t.op, t.ip)).collect();
+        assert_eq!(seen, [("OUTPUT", 2), ("OUTPUT", 8)]);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut cpu = Cpu::new(Some(vec![1101, 1, 2, 0, 99]));
+        assert_eq!(
+            cpu.run_timeout(Duration::from_secs(10)),
+            Ok(RunState::Halted)
+        );
+        assert_eq!(cpu.memory[0], 3);
+
+        let mut cpu = Cpu::new(Some(vec![1105, 1, 0]));
+        assert_eq!(
+            cpu.run_timeout(Duration::from_millis(1)),
+            Ok(RunState::Timeout)
+        );
+        assert!(cpu.cycles() >= 10_000);
+    }
+
+    #[test]
+    fn binary_snapshot() {
+        let mut cpu = Cpu::new(Some(vec![109, -7, 3, 20, 204, 27, 1105, 1, 2, 99]));
+        cpu.verbose = false;
+        cpu.push_input(5);
+        cpu.push_input(6);
+        assert_eq!(cpu.run_n(3), Ok(RunState::Continue));
+
+        let bytes = cpu.to_bytes();
+        let mut restored = Cpu::from_bytes(&bytes).unwrap();
+        assert_eq!(restored, cpu);
+        assert_eq!(restored.rbase, -7);
+        assert_eq!(restored.cycles(), 3);
+        restored.verbose = false;
+        restored.interactive = false;
+        cpu.interactive = false;
+        assert_eq!(cpu.resume(), Ok(RunState::NeedInput));
+        assert_eq!(restored.resume(), Ok(RunState::NeedInput));
+        assert_eq!(restored, cpu);
+        assert_eq!(restored.outputs(), [5, 6]);
+
+        assert!(Cpu::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Cpu::from_bytes(&[bytes.clone(), vec![0]].concat()).is_err());
+
+        // The program loaded is kept apart from memory for reset and diff.
+        assert_eq!(restored.diff(), cpu.diff());
+        assert!(!restored.diff().is_empty());
+        restored.reset();
+        assert_eq!(restored.memory[..4], [109, -7, 3, 20]);
+
+        // Sparse cells survive the round trip.
+        let cpu = CpuBuilder::new()
+            .program(vec![109, 1_000_000, 21101, 3, 4, 0, 99])
+            .verbose(false)
+            .sparse_memory(true)
+            .build();
+        let cpu = cpu.run().unwrap();
+        let restored = Cpu::from_bytes(&cpu.to_bytes()).unwrap();
+        assert_eq!(restored.peek(1_000_000), 7);
+        assert_eq!(restored, cpu);
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        assert_eq!(
+            Cpu::new(Some(vec![42])).run().err(),
+            Some(CpuError::InvalidOpcode(42, 0))
+        );
+        assert_eq!(
+            Cpu::new(Some(vec![1101, 1, 1, 0, 42])).run().err(),
+            Some(CpuError::InvalidOpcode(42, 4))
+        );
+    }
+
+    #[test]
+    fn invalid_mode() {
+        assert_eq!(
+            Cpu::new(Some(vec![304, 0, 99])).run().err(),
+            Some(CpuError::InvalidMode(3))
+        );
+        assert_eq!(
+            Cpu::new(Some(vec![11101, 1, 1, 0, 99])).run().err(),
+            Some(CpuError::InvalidMode(1))
+        );
+    }
+
+    #[test]
+    fn halt_address() {
+        let mut cpu = Cpu::new(Some(vec![1101, 1, 1, 0, 99]));
+        assert_eq!(cpu.halt_ip(), None);
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        assert_eq!(cpu.halt_ip(), Some(4));
+
+        // Branch to one of two exits on the input.
+        let prog = vec![3, 20, 1005, 20, 6, 99, 99];
+        assert_eq!(
+            CpuBuilder::new()
+                .program(prog.clone())
+                .input(0)
+                .build()
+                .run()
+                .unwrap()
+                .halt_ip(),
+            Some(5)
+        );
+        assert_eq!(
+            CpuBuilder::new()
+                .program(prog)
+                .input(1)
+                .build()
+                .run()
+                .unwrap()
+                .halt_ip(),
+            Some(6)
+        );
+    }
+
+    #[test]
+    fn ran_off_end() {
+        assert_eq!(
+            Cpu::new(Some(vec![1101, 1, 2, 0])).run().err(),
+            Some(CpuError::RanOffEnd(4))
+        );
+        assert_eq!(
+            Cpu::new(Some(vec![1105, 1, 100])).run().err(),
+            Some(CpuError::RanOffEnd(100))
+        );
+    }
+
+    #[test]
+    fn negative_address() {
+        assert_eq!(
+            Cpu::new(Some(vec![1105, 1, -1])).run().err(),
+            Some(CpuError::NegativeAddress(-1))
+        );
+        assert_eq!(
+            Cpu::new(Some(vec![4, -3, 99])).run().err(),
+            Some(CpuError::NegativeAddress(-3))
+        );
+        assert_eq!(
+            Cpu::new(Some(vec![21101, 1, 1, -2, 99])).run().err(),
+            Some(CpuError::NegativeAddress(-2))
+        );
+    }
+
+    #[test]
+    fn negative_and_zero_values() {
+        let src = "1101,100,-1,4,0";
+        let prog = parse_program(src).unwrap();
+        assert_eq!(prog, [1101, 100, -1, 4, 0]);
+        assert_eq!(Cpu::new(Some(prog.clone())).to_program_string(), src);
+        let cpu = Cpu::new(Some(prog)).run().unwrap();
+        assert_eq!(cpu.memory[4], 99);
+        assert_eq!(parse_program("-0,0,-1"), Ok(vec![0, 0, -1]));
+
+        // [13] = -5 * 0, [14] = -8 + [13], then output both.
+        let outputs = run_collect(
+            vec![1102, -5, 0, 13, 101, -8, 13, 14, 4, 13, 4, 14, 99, 1, 1],
+            &[],
+        );
+        assert_eq!(outputs, [0, -8]);
+
+        // A stored -7 used as an address is rejected, whether as a write
+        // destination, a jump target or through the relative base.
+        for prog in &[
+            vec![1101, -10, 3, 7, 1001, 5, 0, 0, 99],
+            vec![1101, -10, 3, 9, 106, 0, 9, 99, 0, 0],
+            vec![1101, -10, 3, 9, 9, 9, 204, 0, 99, 0],
+        ] {
+            let mut cpu = CpuBuilder::new().program(prog.clone()).quiet().build();
+            assert_eq!(cpu.resume(), Err(CpuError::NegativeAddress(-7)));
+        }
+    }
+
+    #[test]
+    fn decode_at_end() {
+        let mut cpu = Cpu::new(Some(vec![99, 1]));
+        cpu.ip = 1;
+        assert_eq!(cpu.fetch_and_decode(), Err(CpuError::OutOfBounds(2)));
+        cpu.ip = 2;
+        assert_eq!(cpu.fetch_and_decode(), Err(CpuError::OutOfBounds(2)));
+        assert_eq!(
+            Cpu::new(Some(vec![1105, 1, 3, 1])).run().err(),
+            Some(CpuError::OutOfBounds(4))
+        );
+    }
+
+    #[test]
+    fn trap_context() {
+        let mut cpu = Cpu::new(Some(vec![109, 2, 1105, 1, 5, 1]));
+        let e = cpu.resume().unwrap_err();
+        assert_eq!(e, CpuError::OutOfBounds(6));
+        assert_eq!(cpu.ip(), 5);
+        assert_eq!(cpu.trap(&e), "OutOfBounds(6) at ip=5, rbase=2, op=ADD");
+
+        let msg = std::panic::catch_unwind(|| run_collect(vec![1105, 1, -4], &[]))
+            .unwrap_err()
+            .downcast::<String>()
+            .unwrap();
+        assert!(msg.contains("NegativeAddress(-4) at ip=0"), "{}", msg);
+    }
+
+    #[test]
+    fn strict_modes() {
+        let prog = vec![11104, 7, 99];
+        assert_eq!(run_collect(prog.clone(), &[]), vec![7]);
+        let cpu = CpuBuilder::new()
+            .program(prog)
+            .verbose(false)
+            .strict(true)
+            .build();
+        assert_eq!(cpu.run().err(), Some(CpuError::InvalidMode(1)));
+
+        let cpu = CpuBuilder::new()
+            .program(vec![200003, 0, 99])
+            .strict(true)
+            .build();
+        assert_eq!(cpu.run().err(), Some(CpuError::InvalidMode(2)));
+
+        let cpu = CpuBuilder::new()
+            .program(vec![1101, 1, 1, 0, 99])
+            .strict(true)
+            .build();
+        assert_eq!(cpu.run().unwrap().memory[0], 2);
+    }
+
+    #[test]
+    fn truncated_instruction() {
+        assert_eq!(
+            Cpu::new(Some(vec![1, 0, 0])).run().err(),
+            Some(CpuError::OutOfBounds(3))
+        );
+        assert_eq!(
+            Cpu::new(Some(vec![1, 2, 3])).run().err(),
+            Some(CpuError::OutOfBounds(3))
+        );
+        assert_eq!(arity(1101), 3);
+        assert_eq!(arity(99), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "wide")]
+    fn wide_cells() {
+        let a = i64::MAX as Word;
+        let mut cpu = Cpu::new(Some(vec![1102, a, 3, 7, 4, 7, 99, 0]));
+        cpu.verbose = false;
+        let cpu = cpu.run().unwrap();
+        assert_eq!(cpu.outputs(), [a * 3]);
+        assert!(cpu.outputs()[0] > i64::MAX as Word);
+    }
+
+    #[test]
+    fn recorded_runs() {
+        let eq8 = [3, 9, 8, 9, 10, 9, 4, 9, 99, -1, 8];
+        let lt8 = [3, 3, 1107, -1, 8, 3, 4, 3, 99];
+        let jump = [3, 12, 6, 12, 15, 1, 13, 14, 13, 4, 13, 99, -1, 0, 1, 9];
+        let cmp8 = [
+            3, 21, 1008, 21, 8, 20, 1005, 20, 22, 107, 8, 21, 20, 1006, 20, 31, 1106, 0, 36, 98, 0,
+            0, 1002, 21, 125, 20, 4, 20, 1105, 1, 46, 104, 999, 1105, 1, 46, 1101, 1000, 1, 20, 4,
+            20, 1105, 1, 46, 98, 99,
+        ];
+        let amp = [
+            3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0,
+        ];
+        let quine = [
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        // Program, inputs, expected outputs and how it should stop.
+        type Case<'a> = (&'a [Word], Vec<Word>, Vec<Word>, Result<RunState, CpuError>);
+        let cases: Vec<Case> = vec![
+            (&eq8, vec![8], vec![1], Ok(RunState::Halted)),
+            (&eq8, vec![-8], vec![0], Ok(RunState::Halted)),
+            (&lt8, vec![7], vec![1], Ok(RunState::Halted)),
+            (&lt8, vec![8], vec![0], Ok(RunState::Halted)),
+            (&jump, vec![0], vec![0], Ok(RunState::Halted)),
+            (&jump, vec![3], vec![1], Ok(RunState::Halted)),
+            (&cmp8, vec![-100], vec![999], Ok(RunState::Halted)),
+            (&cmp8, vec![8], vec![1000], Ok(RunState::Halted)),
+            (&cmp8, vec![1 << 40], vec![1001], Ok(RunState::Halted)),
+            (&amp, vec![4, 0], vec![4], Ok(RunState::Halted)),
+            (&amp, vec![3, 4], vec![43], Ok(RunState::Halted)),
+            (&amp, vec![2], vec![], Ok(RunState::NeedInput)),
+            (&quine, vec![], quine.to_vec(), Ok(RunState::Halted)),
+            (
+                &[104, 1125899906842624, 99],
+                vec![],
+                vec![1125899906842624],
+                Ok(RunState::Halted),
+            ),
+            (
+                &[104, 1, 1101, 0, 0, 0],
+                vec![],
+                vec![1],
+                Err(CpuError::RanOffEnd(6)),
+            ),
+        ];
+        for (program, inputs, outputs, state) in cases {
+            assert_eq!(
+                diff_run(program, &inputs),
+                (outputs, state),
+                "program {:?} with inputs {:?}",
+                program,
+                inputs
+            );
+        }
+    }
+
+    #[test]
+    fn day9_examples() {
+        let quine = vec![
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        assert_eq!(run_collect(quine.clone(), &[]), quine);
+
+        let out = run_collect(vec![1102, 34915192, 34915192, 7, 4, 7, 99, 0], &[]);
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].to_string().len(), 16);
+
+        assert_eq!(
+            run_collect(vec![104, 1125899906842624, 99], &[]),
+            [1125899906842624]
+        );
+    }
+}
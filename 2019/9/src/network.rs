@@ -0,0 +1,92 @@
+use crate::{addr, Cpu, CpuError, Word};
+
+// Address of the day 23 NAT.
+pub const NAT: usize = 255;
+
+// One day 23 network packet. Machines send one as three outputs, dest then
+// x then y, and receive one as two inputs, x then y.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Packet {
+    pub dest: usize,
+    pub x: Word,
+    pub y: Word,
+}
+
+impl Cpu {
+    // Queue |p| for the program to read.
+    pub fn send_packet(&mut self, p: &Packet) {
+        self.push_input(p.x);
+        self.push_input(p.y);
+    }
+
+    // Run until the program sends a whole packet and return it, or None if
+    // it stops first. Packets are framed by the buffered outputs, so one cut
+    // short, e.g. by the program waiting for input halfway through, is
+    // finished by the next call; draining outputs mid-packet loses the
+    // framing. A negative dest fails with CpuError::NegativeAddress.
+    pub fn recv_packet(&mut self) -> Result<Option<Packet>, CpuError> {
+        let need = 3 - self.pending_output() % 3;
+        match self.run_until_outputs(need)? {
+            Some(out) if out.len() == need => {}
+            _ => return Ok(None),
+        }
+        let packet = &self.outputs[self.outputs.len() - 3..];
+        Ok(Some(Packet {
+            dest: addr(packet[0])?,
+            x: packet[1],
+            y: packet[2],
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CpuBuilder, InputMode, RunState};
+
+    #[test]
+    fn loopback() {
+        // Forever: read x and y, then send them on to the NAT.
+        let prog = vec![3, 100, 3, 101, 104, 255, 4, 100, 4, 101, 1105, 1, 0];
+        let mut cpu = CpuBuilder::new().program(prog).quiet().build();
+        let p = Packet {
+            dest: 3,
+            x: 5,
+            y: -6,
+        };
+        cpu.send_packet(&p);
+        assert_eq!(cpu.recv_packet(), Ok(Some(Packet { dest: NAT, ..p })));
+        assert_eq!(cpu.recv_packet(), Ok(None));
+        assert_eq!(cpu.step(), Ok(RunState::NeedInput));
+
+        cpu.send_packet(&Packet {
+            dest: 0,
+            x: 1,
+            y: 2,
+        });
+        let p = cpu.recv_packet().unwrap().unwrap();
+        assert_eq!((p.x, p.y), (1, 2));
+
+        // An idle machine reads -1 and sends nothing useful.
+        cpu.input_mode = InputMode::NonBlocking;
+        let p = cpu.recv_packet().unwrap().unwrap();
+        assert_eq!((p.x, p.y), (-1, -1));
+    }
+
+    #[test]
+    fn split_packets() {
+        // Send 1, 2, wait for input, then send 3, 4, 5, 6.
+        let prog = vec![104, 1, 104, 2, 3, 20, 104, 3, 104, 4, 104, 5, 104, 6, 99];
+        let mut cpu = CpuBuilder::new().program(prog).quiet().build();
+        assert_eq!(cpu.recv_packet(), Ok(None));
+        cpu.push_input(0);
+        let packet = |dest, x, y| Ok(Some(Packet { dest, x, y }));
+        assert_eq!(cpu.recv_packet(), packet(1, 2, 3));
+        assert_eq!(cpu.recv_packet(), packet(4, 5, 6));
+        assert_eq!(cpu.recv_packet(), Ok(None));
+
+        let prog = vec![104, -1, 104, 0, 104, 0, 99];
+        let mut cpu = CpuBuilder::new().program(prog).quiet().build();
+        assert_eq!(cpu.recv_packet(), Err(CpuError::NegativeAddress(-1)));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{Cpu, CpuError, Word};
+use std::collections::HashMap;
+
+// Hull painting robot for day 11. Each step the program is given the color
+// of the panel under the robot and answers with the color to paint it and
+// which way to turn (0 left, 1 right) before moving forward one panel.
+pub struct Robot {
+    cpu: Cpu,
+    pos: (i32, i32),
+    // Unit step in the direction faced, with y increasing downward.
+    heading: (i32, i32),
+    panels: HashMap<(i32, i32), Word>,
+}
+
+impl Robot {
+    pub fn new(mut cpu: Cpu) -> Robot {
+        cpu.verbose = false;
+        cpu.interactive = false;
+        Robot {
+            cpu,
+            pos: (0, 0),
+            heading: (0, -1),
+            panels: HashMap::new(),
+        }
+    }
+
+    // Paint a panel before running, e.g. to start on a white panel.
+    pub fn paint(&mut self, pos: (i32, i32), color: Word) {
+        self.panels.insert(pos, color);
+    }
+
+    // Drive the robot until the program halts.
+    pub fn run(&mut self) -> Result<(), CpuError> {
+        loop {
+            let current = self.panels.get(&self.pos).copied().unwrap_or(0);
+            self.cpu.push_input(current);
+            let color = match self.cpu.run_until_output()? {
+                Some(v) => v,
+                None => return Ok(()),
+            };
+            let turn = match self.cpu.run_until_output()? {
+                Some(v) => v,
+                None => return Ok(()),
+            };
+            self.panels.insert(self.pos, color);
+            let (dx, dy) = self.heading;
+            self.heading = if turn == 0 { (dy, -dx) } else { (-dy, dx) };
+            self.pos = (self.pos.0 + self.heading.0, self.pos.1 + self.heading.1);
+        }
+    }
+
+    // Every panel painted at least once and its current color.
+    pub fn panels(&self) -> &HashMap<(i32, i32), Word> {
+        &self.panels
+    }
+
+    // Draw the painted area with '#' for white panels and '.' for the rest.
+    pub fn render(&self) -> String {
+        let xs = self.panels.keys().map(|p| p.0);
+        let ys = self.panels.keys().map(|p| p.1);
+        let (x0, x1) = (xs.clone().min().unwrap_or(0), xs.max().unwrap_or(-1));
+        let (y0, y1) = (ys.clone().min().unwrap_or(0), ys.max().unwrap_or(-1));
+        let mut out = String::new();
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                out.push(match self.panels.get(&(x, y)) {
+                    Some(1) => '#',
+                    _ => '.',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paint_example() {
+        // Store each input from address 100 on and answer with the moves
+        // from the day 11 example.
+        let moves = [(1, 0), (0, 0), (1, 0), (1, 0), (0, 1), (1, 0), (1, 0)];
+        let mut prog = Vec::new();
+        for (i, &(color, turn)) in moves.iter().enumerate() {
+            prog.extend_from_slice(&[3, 100 + i as Word, 104, color, 104, turn]);
+        }
+        prog.push(99);
+
+        let mut robot = Robot::new(Cpu::new(Some(prog)));
+        robot.run().unwrap();
+        assert_eq!(robot.panels().len(), 6);
+        assert_eq!(robot.cpu.peek(104), 1);
+        assert_eq!(robot.render(), "..#\n..#\n##.\n");
+    }
+}
@@ -0,0 +1,38 @@
+use aoc9::{countdown, CpuBuilder, RunState};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+// Counts allocations made on the current thread, so the harness's own
+// threads don't skew the numbers.
+struct Counting;
+
+thread_local! {
+    static ALLOCS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+// Executing instructions shouldn't touch the heap once memory is sized.
+#[test]
+fn no_allocations_per_instruction() {
+    let mut cpu = CpuBuilder::new().program(countdown(10_000)).quiet().build();
+
+    let before = ALLOCS.with(Cell::get);
+    assert_eq!(cpu.resume(), Ok(RunState::Halted));
+    let allocs = ALLOCS.with(Cell::get) - before;
+
+    assert_eq!(cpu.cycles(), 20_001);
+    assert_eq!(allocs, 0);
+}
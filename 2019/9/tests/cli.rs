@@ -0,0 +1,131 @@
+use aoc9::{countdown, CpuBuilder, RunState};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// With logging off the binary should print nothing beyond its own
+// progress lines and the program's OUTPUT values.
+#[test]
+fn no_trace_without_logging() {
+    let path = std::env::temp_dir().join("aoc9_cli_quiet.txt");
+    std::fs::write(&path, "109,5,21101,3,4,0,204,0,99\n").unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_aoc9"))
+        .arg(&path)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "running part 1\n> 7\npart 1: 7\nrunning part 2\n> 7\npart 2: 7\n"
+    );
+    assert!(out.stderr.is_empty());
+}
+
+#[test]
+fn program_from_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aoc9"))
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"104,42,99\n")
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "running part 1\n> 42\npart 1: 42\nrunning part 2\n> 42\npart 2: 42\n"
+    );
+}
+
+// Run the binary on |program| with |stdin| as its input, returning what it
+// printed to stdout and stderr together.
+fn run_with_stdin(name: &str, program: &str, stdin: &[u8]) -> String {
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, program).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aoc9"))
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    let out = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    String::from_utf8(out.stdout).unwrap() + &String::from_utf8(out.stderr).unwrap()
+}
+
+#[test]
+fn stdin_eof_and_bad_input() {
+    // Echo inputs forever. At the end of stdin it must stop rather than
+    // read -1 over and over.
+    let echo = "3,100,4,100,1105,1,0\n";
+    let out = run_with_stdin("aoc9_cli_eof.txt", echo, b"");
+    assert!(out.contains("UnexpectedEof"), "{}", out);
+    assert!(!out.contains("-1"), "{}", out);
+
+    let out = run_with_stdin("aoc9_cli_bad_input.txt", echo, b"abc\n");
+    assert!(out.contains("BadInput"), "{}", out);
+    assert!(!out.contains("panicked"), "{}", out);
+}
+
+// A fault goes to stderr and fails the process, without a part answer.
+#[test]
+fn cpu_fault() {
+    let path = std::env::temp_dir().join("aoc9_cli_fault.txt");
+    std::fs::write(&path, "42\n").unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_aoc9"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "running part 1\n");
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.starts_with("part 1: cpu fault: InvalidOpcode(42, 0)"),
+        "{}",
+        stderr
+    );
+}
+
+// A long headless run must finish promptly and print nothing. The run
+// happens in a child copy of this test binary, so its stdout can be
+// checked between two markers.
+#[test]
+fn quiet_benchmark() {
+    if std::env::var_os("AOC9_QUIET_CHILD").is_some() {
+        let mut cpu = CpuBuilder::new()
+            .program(countdown(100_000))
+            .quiet()
+            .build();
+        println!("<start>");
+        let start = Instant::now();
+        assert_eq!(cpu.resume(), Ok(RunState::Halted));
+        let elapsed = start.elapsed();
+        println!("<end>");
+        assert!(elapsed < Duration::from_secs(10));
+        assert_eq!(cpu.cycles(), 200_001);
+        return;
+    }
+    let out = Command::new(std::env::current_exe().unwrap())
+        .args(["quiet_benchmark", "--exact", "--nocapture"])
+        .env("AOC9_QUIET_CHILD", "1")
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(out.status.success(), "{}", stdout);
+    assert!(stdout.contains("<start>\n<end>\n"), "{}", stdout);
+}